@@ -105,7 +105,28 @@ impl AddressingMode {
 
             (ArgumentKind::Implied,_) => AddressingMode::Implied,
 
-            (c,v) => { println!("{:?} and {:?}", c, v); return Err(AddrModeError) }
+            _ => return Err(AddrModeError)
+        })
+    }
+    // The same kind of operand with another size, e.g. $12,x -> $001234,x
+    pub fn resize(self, size: SizeHint) -> Option<Self> {
+        use self::AddressingMode::*;
+        let index = match self {
+            DirectPage(_) | Absolute(_) | AbsLong(_) => 0,
+            DPX(_) | AbsoluteX(_) | AbsLongX(_) => 1,
+            DPY(_) | AbsoluteY(_) => 2,
+            _ => return None
+        };
+        Some(match (index, size) {
+            (0, SizeHint::Byte) => DirectPage(0),
+            (0, SizeHint::Word) => Absolute(0),
+            (0, SizeHint::Long) => AbsLong(0),
+            (1, SizeHint::Byte) => DPX(0),
+            (1, SizeHint::Word) => AbsoluteX(0),
+            (1, SizeHint::Long) => AbsLongX(0),
+            (2, SizeHint::Byte) => DPY(0),
+            (2, SizeHint::Word) => AbsoluteY(0),
+            _ => return None
         })
     }
     pub fn write_to<W: Write>(&self, mut w: W) -> io::Result<()> {
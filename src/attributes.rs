@@ -1,32 +1,110 @@
-use lexer::Span;
+use std::fmt;
+
+use lexer::{Span,Location};
+use mapper::Mapper;
 
 #[derive(Clone, Debug)]
 pub enum Attribute {
     Bank(u8),
+    // the bank 16-bit data accesses go to, when it isn't the chunk's own bank
+    DataBank(u8),
+    // what the direct page register is assumed to be, $0000 by default
+    DirectPage(u16),
     Pin(u32),
     WarnLength(u16),
     SpanBanks(bool),
+    // rewrite branches that don't reach into BRL (or an inverted branch over one)
+    RelaxBranches,
+    // visibility: private labels can only be used from their own file, export undoes #![private]
+    Private,
+    Export,
+    // a label some other file has to export
+    Import(String),
+    // the chunk takes the place of another one, see `linker::replace_chunks`
+    Replace(String),
+    Mapper(Mapper),
+    // in bytes, given in KiB
+    MaxRomSize(usize),
+    // internal header fields, see header.rs
+    Title(String),
+    Region(u8),
+    Maker(u8),
+    Version(u8),
+    Sram(u8),
+    Chipset(u8),
     Start,
     NMI,
     IRQ,
-    BRK
+    BRK,
+    // vectors of the SA-1 CPU, which are set through its registers instead of the header
+    SA1Start,
+    SA1NMI,
+    SA1IRQ
 }
 
 #[derive(Debug)]
 pub enum AttributeError {
     WrongArgType,
     UnexpectedEnd,
-    NotFound,
+    NotFound(Span),
     WrongAttrName(Span),
+    InvalidValue(Span, &'static str),
     Other
 }
 
+impl fmt::Display for AttributeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::AttributeError::*;
+        match self {
+            WrongArgType => write!(f, "wrong argument type for attribute"),
+            UnexpectedEnd => write!(f, "unexpected end of attribute"),
+            NotFound(s) => write!(f, "unknown attribute {}", s),
+            WrongAttrName(s) => write!(f, "expected an attribute name, found {}", s),
+            InvalidValue(s, reason) => write!(f, "invalid attribute value {}: {}", s, reason),
+            Other => write!(f, "invalid attribute")
+        }
+    }
+}
+
+impl AttributeError {
+    pub fn location(&self) -> Option<(Location, u32)> {
+        use self::AttributeError::*;
+        match self {
+            NotFound(s) | WrongAttrName(s) | InvalidValue(s, _) => s.location(),
+            _ => None
+        }
+    }
+}
+
 
 
 impl Attribute {
+    // Interrupt vectors the linker has to fill in, by the name used in errors
+    pub fn vector_name(&self) -> Option<&'static str> {
+        use self::Attribute::*;
+        Some(match self {
+            Start => "Start",
+            NMI => "NMI",
+            IRQ => "IRQ",
+            BRK => "BRK",
+            SA1Start => "SA1Start",
+            SA1NMI => "SA1NMI",
+            SA1IRQ => "SA1IRQ",
+            _ => return None
+        })
+    }
     pub fn from_span(s: &[Span]) -> Result<Self,AttributeError> {
         use self::Attribute::*;
         use self::AttributeError::*;
+        let arg = || s.get(2).ok_or(UnexpectedEnd);
+        let byte = || {
+            let c = arg()?;
+            match c.as_number() {
+                Some(n) if n >= 0 && n <= 0xFF => Ok(n as u8),
+                Some(_) => Err(InvalidValue(c.clone(), "has to fit in a byte")),
+                None => Err(WrongArgType)
+            }
+        };
         let ident = s.get(0)
             .ok_or(UnexpectedEnd)?;
         let ident = ident
@@ -36,17 +114,130 @@ impl Attribute {
             "bank" => {
                 Bank(s.get(2).ok_or(UnexpectedEnd)?.as_number().ok_or(WrongArgType)? as u8)
             },
+            "data_bank" => DataBank(byte()?),
+            "direct_page" => {
+                let c = arg()?;
+                match c.as_number() {
+                    Some(n) if n >= 0 && n <= 0xFFFF => DirectPage(n as u16),
+                    Some(_) => return Err(InvalidValue(c.clone(), "has to fit in a word")),
+                    None => return Err(WrongArgType)
+                }
+            },
+            "pin" => {
+                let c = arg()?;
+                match c.as_number() {
+                    Some(n) if n >= 0 && n <= 0xFFFFFF => Pin(n as u32),
+                    Some(_) => return Err(InvalidValue(c.clone(), "has to be a 24-bit address")),
+                    None => return Err(WrongArgType)
+                }
+            },
             "span_banks" => {
                 SpanBanks(s.get(2).map(|c| {
                     c.as_ident().ok_or(AttributeError::WrongArgType)?
                         .parse().map_err(|_| AttributeError::WrongArgType)
                 }).unwrap_or(Ok(true))?)
             },
+            "relax_branches" => RelaxBranches,
+            "private" => Private,
+            "export" => Export,
+            "import" => Import(arg()?.as_ident().ok_or(WrongArgType)?.to_string()),
+            "replace" => Replace(arg()?.as_ident().ok_or(WrongArgType)?.to_string()),
+            "mapper" => {
+                let name = s.get(2).ok_or(UnexpectedEnd)?.as_ident().ok_or(WrongArgType)?;
+                Mapper(::mapper::Mapper::parse(name).ok_or(WrongArgType)?)
+            },
+            "title" => {
+                let c = arg()?;
+                let title = c.clone().as_string().ok_or(WrongArgType)?;
+                if !title.is_ascii() { return Err(InvalidValue(c.clone(), "only ASCII is allowed")) }
+                if title.len() > 21 { return Err(InvalidValue(c.clone(), "can be at most 21 characters long")) }
+                Title(title)
+            },
+            "region" => Region(match arg()?.as_ident() {
+                Some(c) => region_code(c).ok_or(InvalidValue(arg()?.clone(), "unknown region"))?,
+                None => byte()?
+            }),
+            "maker" => Maker(byte()?),
+            "version" => Version(byte()?),
+            // in KiB
+            "sram" => {
+                let c = arg()?;
+                match c.as_number() {
+                    Some(0) => Sram(0),
+                    Some(n) if n > 0 && n <= 0x400 && n & (n - 1) == 0 => Sram(n.trailing_zeros() as u8),
+                    Some(_) => return Err(InvalidValue(c.clone(), "has to be a power of two in KiB, up to 1024")),
+                    None => return Err(WrongArgType)
+                }
+            },
+            "chipset" => Chipset(match arg()?.as_ident() {
+                Some(c) => chipset_code(c).ok_or(InvalidValue(arg()?.clone(), "unknown chipset"))?,
+                None => byte()?
+            }),
+            "max_rom_size" => {
+                let c = arg()?;
+                match c.as_number() {
+                    Some(n) if n > 0 => MaxRomSize(n as usize * 0x400),
+                    Some(_) => return Err(InvalidValue(c.clone(), "has to be at least 1 KiB")),
+                    None => return Err(WrongArgType)
+                }
+            },
             "start" => Start,
             "nmi" => NMI,
             "irq" => IRQ,
             "brk" => BRK,
-            _ => return Err(NotFound)
+            "sa1_start" => SA1Start,
+            "sa1_nmi" => SA1NMI,
+            "sa1_irq" => SA1IRQ,
+            _ => return Err(NotFound(s[0].clone()))
         })
     }
 }
+
+// For global attributes, which may only be set once (but are repeated on every chunk after them).
+// Returns `name` if the field is already set to something else.
+pub fn set_once<T: PartialEq + Clone>(field: &mut Option<T>, value: &T, name: &'static str) -> Result<(), &'static str> {
+    match field {
+        Some(c) if c != value => Err(name),
+        _ => { *field = Some(value.clone()); Ok(()) }
+    }
+}
+
+fn region_code(name: &str) -> Option<u8> {
+    Some(match name {
+        "japan" => 0x00,
+        "usa" | "ntsc" => 0x01,
+        "europe" | "pal" => 0x02,
+        "sweden" => 0x03,
+        "finland" => 0x04,
+        "denmark" => 0x05,
+        "france" => 0x06,
+        "netherlands" => 0x07,
+        "spain" => 0x08,
+        "germany" => 0x09,
+        "italy" => 0x0A,
+        "china" => 0x0B,
+        "indonesia" => 0x0C,
+        "korea" => 0x0D,
+        "international" => 0x0E,
+        "canada" => 0x0F,
+        "brazil" => 0x10,
+        "australia" => 0x11,
+        _ => return None
+    })
+}
+
+// The cartridge type byte, the names stand for the usual configuration with RAM and battery
+fn chipset_code(name: &str) -> Option<u8> {
+    Some(match name {
+        "rom" => 0x00,
+        "ram" => 0x01,
+        "battery" => 0x02,
+        "dsp" => 0x05,
+        "superfx" => 0x15,
+        "obc1" => 0x25,
+        "sa1" => 0x35,
+        "sdd1" => 0x45,
+        "srtc" => 0x55,
+        _ => return None
+    })
+}
@@ -2,9 +2,11 @@ extern crate piped_asm;
 
 use std::process;
 
+use piped_asm::diagnostics::Diagnostic;
+
 fn main() {
     if let Err(e) = piped_asm::run() {
-        println!("\x1B[1;31mError\x1B[0m: {}", e);
+        Diagnostic::error(e.to_string()).emit();
         process::exit(1);
     }
 }
@@ -0,0 +1,228 @@
+// BPS patches: "BPS1", the source, target and metadata sizes, the metadata, then actions that
+// build the target front to back, and the CRC32s of the source, the target and the patch itself.
+// Numbers are variable length, 7 bits at a time with the top bit set on the last byte.
+// Every action is a number with the length - 1 above the 2 bit kind:
+//   SourceRead: the same bytes as in the source at this offset
+//   TargetRead: new bytes, right after it
+//   SourceCopy, TargetCopy: bytes from elsewhere in the source or what's already written, at a
+//     signed offset from where the last copy of the same kind ended
+
+use std::io;
+
+const SOURCE_READ: usize = 0;
+const TARGET_READ: usize = 1;
+const SOURCE_COPY: usize = 2;
+const TARGET_COPY: usize = 3;
+// Runs of the same byte shorter than this aren't worth a TargetCopy
+const MIN_RUN: usize = 4;
+// Unchanged bytes that are cheaper to repeat in a TargetRead than to SourceRead
+const MAX_GAP: usize = 2;
+
+// The usual CRC32 (IEEE 802.3, reflected)
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut table = [0u32; 256];
+    for (i, entry) in table.iter_mut().enumerate() {
+        *entry = (0..8).fold(i as u32, |c, _| if c & 1 != 0 { 0xEDB88320 ^ c >> 1 } else { c >> 1 });
+    }
+    !data.iter().fold(!0, |crc, c| table[((crc ^ *c as u32) & 0xFF) as usize] ^ crc >> 8)
+}
+
+fn write_number(out: &mut Vec<u8>, mut data: usize) {
+    loop {
+        let x = (data & 0x7F) as u8;
+        data >>= 7;
+        if data == 0 {
+            out.push(0x80 | x);
+            break;
+        }
+        out.push(x);
+        data -= 1;
+    }
+}
+
+fn read_number(patch: &[u8], pos: &mut usize) -> io::Result<usize> {
+    let mut data = 0usize;
+    let mut shift = 1usize;
+    loop {
+        let x = *patch.get(*pos).ok_or_else(|| invalid("cut off"))?;
+        *pos += 1;
+        data = shift.checked_mul((x & 0x7F) as usize).and_then(|c| c.checked_add(data)).ok_or_else(|| invalid("number too big"))?;
+        if x & 0x80 != 0 { return Ok(data); }
+        shift = shift.checked_mul(0x80).ok_or_else(|| invalid("number too big"))?;
+        data = data.checked_add(shift).ok_or_else(|| invalid("number too big"))?;
+    }
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("broken BPS patch: {}", what))
+}
+
+// A patch that turns `source` into `target`. Things only move around when a ROM is linked from
+// scratch, so it just reads unchanged bytes from the source, writes the new ones, and repeats
+// runs of the same byte (like the zeros of an expanded ROM) with TargetCopy.
+pub fn write(source: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut patch = b"BPS1".to_vec();
+    write_number(&mut patch, source.len());
+    write_number(&mut patch, target.len());
+    write_number(&mut patch, 0);
+    let same = |i: usize| source.get(i) == Some(&target[i]);
+    let mut target_offset = 0;
+    let mut pos = 0;
+    while pos < target.len() {
+        let unchanged = (pos..target.len()).take_while(|c| same(*c)).count();
+        if unchanged > 0 {
+            write_number(&mut patch, (unchanged - 1) << 2 | SOURCE_READ);
+            pos += unchanged;
+            continue;
+        }
+        // new bytes up to the next unchanged stretch or run of the same byte
+        let mut end = pos;
+        while end < target.len() {
+            let run = target[end..].iter().take_while(|c| **c == target[end]).count();
+            let gap = (end..target.len()).take_while(|c| same(*c)).count();
+            if run >= MIN_RUN || gap > MAX_GAP || end + gap == target.len() && gap > 0 { break; }
+            end += gap.max(1);
+        }
+        if end > pos {
+            write_number(&mut patch, (end - pos - 1) << 2 | TARGET_READ);
+            patch.extend_from_slice(&target[pos..end]);
+            pos = end;
+            continue;
+        }
+        // a run: its first byte, then copies of the byte before
+        let run = target[pos..].iter().take_while(|c| **c == target[pos]).count();
+        write_number(&mut patch, TARGET_READ);
+        patch.push(target[pos]);
+        let delta = pos as isize - target_offset as isize;
+        write_number(&mut patch, (run - 2) << 2 | TARGET_COPY);
+        write_number(&mut patch, (delta.abs() as usize) << 1 | (delta < 0) as usize);
+        target_offset = pos + run - 1;
+        pos += run;
+    }
+    for c in [crc32(source), crc32(target)].iter() {
+        patch.extend_from_slice(&[*c as u8, (*c >> 8) as u8, (*c >> 16) as u8, (*c >> 24) as u8]);
+    }
+    let crc = crc32(&patch);
+    patch.extend_from_slice(&[crc as u8, (crc >> 8) as u8, (crc >> 16) as u8, (crc >> 24) as u8]);
+    patch
+}
+
+// Applies a patch to the ROM it was made for
+pub fn apply(source: &[u8], patch: &[u8]) -> io::Result<Vec<u8>> {
+    if patch.len() < 16 || &patch[..4] != b"BPS1" { return Err(invalid("not a BPS patch")); }
+    let footer = patch.len() - 12;
+    let crc = |pos: usize| patch[pos] as u32 | (patch[pos + 1] as u32) << 8 | (patch[pos + 2] as u32) << 16 | (patch[pos + 3] as u32) << 24;
+    if crc32(&patch[..footer + 8]) != crc(footer + 8) { return Err(invalid("the checksum doesn't match")); }
+    if crc32(source) != crc(footer) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "the patch was made for a different ROM"));
+    }
+    let mut pos = 4;
+    let source_size = read_number(patch, &mut pos)?;
+    let target_size = read_number(patch, &mut pos)?;
+    let metadata = read_number(patch, &mut pos)?;
+    pos = pos.checked_add(metadata).filter(|c| *c <= footer).ok_or_else(|| invalid("cut off"))?;
+    if source_size != source.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "the patch was made for a ROM of a different size"));
+    }
+    let mut target = Vec::with_capacity(target_size.min(0x1000000));
+    let (mut source_offset, mut target_offset) = (0isize, 0isize);
+    while pos < footer {
+        let action = read_number(patch, &mut pos)?;
+        let len = (action >> 2) + 1;
+        if target.len() + len > target_size { return Err(invalid("writes past the end")); }
+        match action & 3 {
+            SOURCE_READ => {
+                let start = target.len();
+                target.extend_from_slice(source.get(start..start + len).ok_or_else(|| invalid("reads past the end"))?);
+            },
+            TARGET_READ => {
+                target.extend_from_slice(patch.get(pos..pos + len).filter(|_| pos + len <= footer).ok_or_else(|| invalid("cut off"))?);
+                pos += len;
+            },
+            kind => {
+                let data = read_number(patch, &mut pos)?;
+                let delta = if data & 1 != 0 { -((data >> 1) as isize) } else { (data >> 1) as isize };
+                let offset = if kind == SOURCE_COPY { &mut source_offset } else { &mut target_offset };
+                *offset += delta;
+                for _ in 0..len {
+                    let byte = if kind == SOURCE_COPY { source.get(*offset as usize) } else { target.get(*offset as usize) };
+                    let byte = *byte.filter(|_| *offset >= 0).ok_or_else(|| invalid("copies from outside the ROM"))?;
+                    target.push(byte);
+                    *offset += 1;
+                }
+            }
+        }
+    }
+    if target.len() != target_size { return Err(invalid("the ROM comes out too short")); }
+    if crc32(&target) != crc(footer + 4) { return Err(invalid("the patched ROM's checksum doesn't match")); }
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+        assert_eq!(crc32(b""), 0);
+    }
+    #[test]
+    fn numbers() {
+        for &c in [0, 1, 0x7F, 0x80, 0x407F, 0x4080, 0x123456, usize::max_value() >> 8].iter() {
+            let mut buf = Vec::new();
+            write_number(&mut buf, c);
+            let mut pos = 0;
+            assert_eq!(read_number(&buf, &mut pos).unwrap(), c);
+            assert_eq!(pos, buf.len());
+        }
+        let mut buf = Vec::new();
+        write_number(&mut buf, 0x80);
+        assert_eq!(buf, vec![0x00, 0x80]);
+    }
+    #[test]
+    fn round_trip() {
+        let source = (0..0x20000).map(|c| (c * 7 >> 2) as u8).collect::<Vec<_>>();
+        let mut target = source.clone();
+        target[0] ^= 1;
+        target[0x10] ^= 1;
+        target[0x12] ^= 1;
+        for c in target[0x1000..0x1800].iter_mut() { *c = 0xFF; }
+        target.resize(0x30000, 0);
+        target[0x2FFFF] = 1;
+        let patch = write(&source, &target);
+        assert!(patch.len() < 0x100);
+        assert_eq!(apply(&source, &patch).unwrap(), target);
+        // shrinking works too
+        let patch = write(&source, &source[..0x100]);
+        assert_eq!(apply(&source, &patch).unwrap(), &source[..0x100]);
+        let mut other = source.clone();
+        other[5] ^= 1;
+        assert!(apply(&other, &patch).is_err());
+        let mut broken = patch.clone();
+        broken[6] ^= 1;
+        assert!(apply(&source, &broken).is_err());
+    }
+    #[test]
+    fn copies() {
+        // the kind of patch other tools write for moved data
+        let source = b"ABCDEFGH";
+        let target = b"EFGHEFABCFGH!";
+        let mut patch = b"BPS1".to_vec();
+        for &c in [8, 13, 0].iter() { write_number(&mut patch, c); }
+        write_number(&mut patch, 3 << 2 | SOURCE_COPY);
+        write_number(&mut patch, 4 << 1);
+        write_number(&mut patch, 1 << 2 | SOURCE_READ);
+        write_number(&mut patch, 2 << 2 | SOURCE_COPY);
+        write_number(&mut patch, 8 << 1 | 1);
+        write_number(&mut patch, 2 << 2 | TARGET_COPY);
+        write_number(&mut patch, 1 << 1);
+        write_number(&mut patch, TARGET_READ);
+        patch.push(b'!');
+        for c in [crc32(source), crc32(target)].iter() {
+            patch.extend_from_slice(&[*c as u8, (*c >> 8) as u8, (*c >> 16) as u8, (*c >> 24) as u8]);
+        }
+        let crc = crc32(&patch);
+        patch.extend_from_slice(&[crc as u8, (crc >> 8) as u8, (crc >> 16) as u8, (crc >> 24) as u8]);
+        assert_eq!(apply(source, &patch).unwrap(), &target[..]);
+    }
+}
@@ -0,0 +1,373 @@
+// Command line parsing for the `piped` binary.
+// Hand-rolled on purpose, the option set is small and doesn't warrant a dependency.
+
+use std::fmt;
+use std::error::Error;
+use std::path::{Path,PathBuf};
+
+// the most verbose anything gets, more -v flags don't change anything
+const MAX_VERBOSITY: u8 = 3;
+
+pub const USAGE: &str = "\
+Usage: piped [command] [options] <input>..
+
+Commands:
+    build       assemble and link a source file into a ROM (default)
+    check       assemble and link without writing any output
+    compile     assemble a source file into an object file (default output: <input>.o)
+    link        link object files into a ROM
+    disasm      disassemble a ROM
+
+Options:
+    -o, --output <file>     output path (default: out.<format>)
+    -I, --include <dir>     add a directory to the incsrc/incbin search path
+    -D <name>=<value>       define a label, overriding the source
+        --mapper <name>     memory mapper (lorom, hirom, exlorom, exhirom, sa1)
+        --max-rom-size <n>  maximum ROM size in KiB (default: whatever the mapper supports)
+    -f, --format <format>   output format (sfc, smc, ips/bps: a patch for the --base ROM)
+        --base <rom>        patch this ROM instead of starting from an empty one
+        --base-patch <bps>  apply this BPS patch to the --base ROM first
+        --freespace-start <address>
+                            SNES address to look for free space in the base ROM from
+                            (default: $108000)
+        --symbols <format>  also write a symbol file next to the output (wla, mlb, json)
+        --listing           also write an assembly listing next to the output (.lst)
+        --debug-info        also write which source line every ROM range came from (.dbg)
+    -v, --verbose           print more information, can be repeated
+    -q, --quiet             only print errors
+        --start <address>   disasm: SNES address to start at (default: reset vector)
+        --count <n>         disasm: amount of instructions (default: 64)
+    -h, --help              print this message";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    Build,
+    Check,
+    Compile,
+    Link,
+    Disasm
+}
+
+impl Command {
+    fn parse(s: &str) -> Option<Self> {
+        use self::Command::*;
+        Some(match s {
+            "build" => Build,
+            "check" => Check,
+            "compile" => Compile,
+            "link" => Link,
+            "disasm" => Disasm,
+            _ => return None
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Sfc,    // plain ROM image
+    Smc,    // ROM image with a 512 byte copier header
+    Ips,    // patch for the base ROM
+    Bps     // same, with checksums and moved data
+}
+
+impl OutputFormat {
+    pub fn parse(s: &str) -> Option<Self> {
+        use self::OutputFormat::*;
+        Some(match &*s.to_lowercase() {
+            "sfc" => Sfc,
+            "smc" => Smc,
+            "ips" => Ips,
+            "bps" => Bps,
+            _ => return None
+        })
+    }
+    pub fn is_patch(self) -> bool {
+        self == OutputFormat::Ips || self == OutputFormat::Bps
+    }
+    pub fn extension(self) -> &'static str {
+        use self::OutputFormat::*;
+        match self {
+            Sfc => "sfc",
+            Smc => "smc",
+            Ips => "ips",
+            Bps => "bps"
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SymbolFormat {
+    Wla,    // WLA-DX .sym, read by bsnes-plus
+    Mlb,    // Mesen label file
+    Json
+}
+
+impl SymbolFormat {
+    pub fn parse(s: &str) -> Option<Self> {
+        use self::SymbolFormat::*;
+        Some(match &*s.to_lowercase() {
+            "wla" | "sym" => Wla,
+            "mlb" | "mesen" => Mlb,
+            "json" => Json,
+            _ => return None
+        })
+    }
+    pub fn extension(self) -> &'static str {
+        use self::SymbolFormat::*;
+        match self {
+            Wla => "sym",
+            Mlb => "mlb",
+            Json => "symbols.json"
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Options {
+    pub command: Command,
+    pub inputs: Vec<String>,
+    pub output: Option<String>,
+    pub include_dirs: Vec<PathBuf>,
+    pub defines: Vec<(String, String)>,
+    pub mapper: Option<String>,
+    // in KiB
+    pub max_rom_size: Option<u32>,
+    pub format: OutputFormat,
+    // ROM to patch
+    pub base: Option<String>,
+    // applied to the base ROM before linking
+    pub base_patch: Option<String>,
+    pub freespace_start: Option<u32>,
+    pub symbols: Option<SymbolFormat>,
+    pub listing: bool,
+    pub debug_info: bool,
+    // 0: errors only, 1: summary, 2: chunk placement, 3: everything
+    pub verbosity: u8,
+    pub start: Option<u32>,
+    pub count: Option<usize>,
+    pub help: bool
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            command: Command::Build,
+            inputs: Vec::new(),
+            output: None,
+            include_dirs: Vec::new(),
+            defines: Vec::new(),
+            mapper: None,
+            max_rom_size: None,
+            format: OutputFormat::Sfc,
+            base: None,
+            base_patch: None,
+            freespace_start: None,
+            symbols: None,
+            listing: false,
+            debug_info: false,
+            verbosity: 1,
+            start: None,
+            count: None,
+            help: false
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CliError {
+    MissingValue(String),
+    InvalidValue(String, String),
+    UnknownFlag(String),
+    MissingInput,
+    TooManyInputs
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::CliError::*;
+        match self {
+            MissingValue(flag) => write!(f, "missing value for {}", flag),
+            InvalidValue(flag, val) => write!(f, "invalid value for {}: {}", flag, val),
+            UnknownFlag(flag) => write!(f, "unknown flag {} (see --help)", flag),
+            MissingInput => write!(f, "no input files (see --help)"),
+            TooManyInputs => write!(f, "too many input files for this command")
+        }
+    }
+}
+
+impl Error for CliError {}
+
+// Accepts $1234, 0x1234 and plain decimal numbers
+pub fn parse_number(s: &str) -> Option<u32> {
+    if s.starts_with('$') {
+        u32::from_str_radix(&s[1..], 16).ok()
+    } else if s.starts_with("0x") || s.starts_with("0X") {
+        u32::from_str_radix(&s[2..], 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+impl Options {
+    pub fn parse<I: IntoIterator<Item=String>>(args: I) -> Result<Self, CliError> {
+        use self::CliError::*;
+        let mut opts = Options::default();
+        let mut args = args.into_iter().peekable();
+        if let Some(c) = args.peek().and_then(|c| Command::parse(c)) {
+            opts.command = c;
+            args.next();
+        }
+        while let Some(arg) = args.next() {
+            // allow both `-o file` and `-ofile` / `--output=file`
+            let (flag, inline) = if arg.starts_with("--") {
+                match arg.find('=') {
+                    Some(c) => (arg[..c].to_string(), Some(arg[c+1..].to_string())),
+                    None => (arg.clone(), None)
+                }
+            } else if arg.starts_with('-') && arg.len() > 2 && !arg.starts_with("-v") && !arg.starts_with("-q") {
+                (arg[..2].to_string(), Some(arg[2..].to_string()))
+            } else {
+                (arg.clone(), None)
+            };
+            let mut value = || inline.clone().or_else(|| args.next()).ok_or(MissingValue(flag.clone()));
+            match &*flag {
+                "-h" | "--help" => opts.help = true,
+                "-o" | "--output" => opts.output = Some(value()?),
+                "-I" | "--include" => opts.include_dirs.push(PathBuf::from(value()?)),
+                "-D" | "--define" => {
+                    let def = value()?;
+                    let mut split = def.splitn(2, '=');
+                    let name = split.next().unwrap_or("").trim().to_string();
+                    // `-D name` alone defines it as 1, same as a C compiler
+                    let val = split.next().unwrap_or("1").trim().to_string();
+                    if name.is_empty() { return Err(InvalidValue(flag, def)); }
+                    opts.defines.push((name, val));
+                },
+                "--mapper" => opts.mapper = Some(value()?.to_lowercase()),
+                "--max-rom-size" => {
+                    let val = value()?;
+                    opts.max_rom_size = Some(parse_number(&val).filter(|c| *c > 0).ok_or(InvalidValue(flag, val))?);
+                },
+                "-f" | "--format" => {
+                    let val = value()?;
+                    opts.format = OutputFormat::parse(&val).ok_or(InvalidValue(flag, val))?;
+                },
+                "--base" => opts.base = Some(value()?),
+                "--base-patch" => opts.base_patch = Some(value()?),
+                "--freespace-start" => {
+                    let val = value()?;
+                    opts.freespace_start = Some(parse_number(&val).ok_or(InvalidValue(flag, val))?);
+                },
+                "--symbols" => {
+                    let val = value()?;
+                    opts.symbols = Some(SymbolFormat::parse(&val).ok_or(InvalidValue(flag, val))?);
+                },
+                "--listing" => opts.listing = true,
+                "--debug-info" => opts.debug_info = true,
+                "-q" | "--quiet" => opts.verbosity = 0,
+                "--start" => {
+                    let val = value()?;
+                    opts.start = Some(parse_number(&val).ok_or(InvalidValue(flag, val))?);
+                },
+                "--count" => {
+                    let val = value()?;
+                    opts.count = Some(parse_number(&val).ok_or(InvalidValue(flag, val))? as usize);
+                },
+                c if c.starts_with("-v") && c[1..].chars().all(|c| c == 'v') => {
+                    let more = (c.len() - 1).min(MAX_VERBOSITY as usize) as u8;
+                    opts.verbosity = opts.verbosity.saturating_add(more).min(MAX_VERBOSITY);
+                },
+                "--verbose" => opts.verbosity = opts.verbosity.saturating_add(1).min(MAX_VERBOSITY),
+                "-" => opts.inputs.push(arg),
+                c if c.starts_with('-') => return Err(UnknownFlag(arg)),
+                _ => opts.inputs.push(arg)
+            }
+        }
+        if opts.help { return Ok(opts); }
+        match opts.command {
+            Command::Link => {},
+            // `piped <input> <output>` still works like it used to
+            Command::Build if opts.inputs.len() == 2 && opts.output.is_none() => {
+                opts.output = opts.inputs.pop();
+            },
+            _ if opts.inputs.len() > 1 => return Err(TooManyInputs),
+            _ => {}
+        }
+        if opts.inputs.is_empty() { return Err(MissingInput); }
+        // patches are made against the base ROM
+        if (opts.format.is_patch() || opts.base_patch.is_some()) && opts.base.is_none() { return Err(MissingValue("--base".to_string())); }
+        Ok(opts)
+    }
+    pub fn output_filename(&self) -> String {
+        if let Some(ref c) = self.output { return c.clone(); }
+        match self.command {
+            Command::Compile if self.inputs[0] != "-" => {
+                Path::new(&self.inputs[0]).with_extension("o").to_string_lossy().into_owned()
+            },
+            Command::Compile => "out.o".to_string(),
+            _ => format!("out.{}", self.format.extension())
+        }
+    }
+    // the output's name with the symbol format's extension
+    pub fn symbols_filename(&self) -> Option<String> {
+        let format = self.symbols?;
+        Some(Path::new(&self.output_filename()).with_extension(format.extension()).to_string_lossy().into_owned())
+    }
+    pub fn listing_filename(&self) -> Option<String> {
+        if !self.listing { return None; }
+        Some(Path::new(&self.output_filename()).with_extension("lst").to_string_lossy().into_owned())
+    }
+    pub fn debug_info_filename(&self) -> Option<String> {
+        if !self.debug_info { return None; }
+        Some(Path::new(&self.output_filename()).with_extension("dbg").to_string_lossy().into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn parse(s: &str) -> Result<Options, CliError> {
+        Options::parse(s.split_whitespace().map(String::from))
+    }
+    #[test]
+    fn legacy() {
+        let opts = parse("main.asm rom.sfc").unwrap();
+        assert_eq!(opts.command, Command::Build);
+        assert_eq!(opts.inputs, vec!["main.asm".to_string()]);
+        assert_eq!(opts.output_filename(), "rom.sfc");
+    }
+    #[test]
+    fn flags() {
+        let opts = parse("build -vv -Iinc -I gfx -D Lives=5 -DDEBUG --format=smc main.asm").unwrap();
+        assert_eq!(opts.verbosity, 3);
+        assert_eq!(parse(&format!("build -{} main.asm", "v".repeat(300))).unwrap().verbosity, MAX_VERBOSITY);
+        assert_eq!(parse(&format!("build {}main.asm", "--verbose ".repeat(300))).unwrap().verbosity, MAX_VERBOSITY);
+        assert_eq!(opts.include_dirs, vec![PathBuf::from("inc"), PathBuf::from("gfx")]);
+        assert_eq!(opts.defines, vec![("Lives".to_string(), "5".to_string()), ("DEBUG".to_string(), "1".to_string())]);
+        assert_eq!(opts.output_filename(), "out.smc");
+        assert_eq!(parse("build --base smw.sfc hack.asm").unwrap().base, Some("smw.sfc".to_string()));
+        assert_eq!(parse("build -f ips --base smw.sfc hack.asm").unwrap().output_filename(), "out.ips");
+        assert_eq!(parse("build -f ips hack.asm").unwrap_err(), CliError::MissingValue("--base".to_string()));
+        assert_eq!(parse("build -f bps --base smw.sfc hack.asm").unwrap().output_filename(), "out.bps");
+        assert_eq!(parse("build --base smw.sfc --base-patch fix.bps hack.asm").unwrap().base_patch, Some("fix.bps".to_string()));
+        assert_eq!(parse("build --base-patch fix.bps hack.asm").unwrap_err(), CliError::MissingValue("--base".to_string()));
+        assert_eq!(parse("build -o hack.sfc --symbols wla hack.asm").unwrap().symbols_filename(), Some("hack.sym".to_string()));
+        assert_eq!(parse("build --symbols json hack.asm").unwrap().symbols_filename(), Some("out.symbols.json".to_string()));
+        assert_eq!(parse("build hack.asm").unwrap().symbols_filename(), None);
+        assert!(parse("build --symbols nope hack.asm").is_err());
+        assert_eq!(parse("build -o hack.sfc --listing hack.asm").unwrap().listing_filename(), Some("hack.lst".to_string()));
+        assert_eq!(parse("build --debug-info hack.asm").unwrap().debug_info_filename(), Some("out.dbg".to_string()));
+        assert_eq!(parse("disasm --start $008000 out.sfc").unwrap().start, Some(0x8000));
+        assert_eq!(parse("check -x main.asm").unwrap_err(), CliError::UnknownFlag("-x".to_string()));
+        assert_eq!(parse("check").unwrap_err(), CliError::MissingInput);
+    }
+    #[test]
+    fn objects() {
+        assert_eq!(parse("compile src/main.asm").unwrap().output_filename(), "src/main.o");
+        assert_eq!(parse("compile -o a.o main.asm").unwrap().output_filename(), "a.o");
+        assert_eq!(parse("compile a.asm b.asm").unwrap_err(), CliError::TooManyInputs);
+        let opts = parse("link main.o sound.o -o rom.sfc").unwrap();
+        assert_eq!(opts.inputs, vec!["main.o".to_string(), "sound.o".to_string()]);
+        assert_eq!(opts.output_filename(), "rom.sfc");
+    }
+}
@@ -1,5 +1,30 @@
 use std::fmt::{self,Display};
 use std::ops::Add;
+use std::env;
+use std::sync::atomic::{AtomicBool,Ordering};
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+#[cfg(unix)]
+fn is_tty(fd: i32) -> bool {
+    extern "C" { fn isatty(fd: i32) -> i32; }
+    unsafe { isatty(fd) != 0 }
+}
+#[cfg(not(unix))]
+fn is_tty(_fd: i32) -> bool {
+    false
+}
+
+// Colors are only used when both stdout and stderr are terminals, and NO_COLOR isn't set
+pub fn init() {
+    set_enabled(env::var_os("NO_COLOR").is_none() && is_tty(1) && is_tty(2));
+}
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
 
 pub struct PrettyPrinted<D> {
     content: D,
@@ -9,6 +34,7 @@ pub struct PrettyPrinted<D> {
 
 impl<D: Display> fmt::Display for PrettyPrinted<D> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !enabled() { return write!(f, "{}", self.content); }
         f.write_str("\x1B[0")?;
         if let Some(c) = self.color {
             write!(f, ";38;5;{}",c)?;
@@ -2,11 +2,14 @@
 use std::collections::HashMap;
 use std::mem;
 use std::error::Error;
+use std::path::{Path,PathBuf};
 
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::fmt;
+use std::io;
 
-use lexer::{Lexer,Span,SpanData};
+use lexer::{Lexer,Span,SpanData,Location};
 
 use parser::{Parser,Statement,ParseError};
 use instructions;
@@ -22,10 +25,50 @@ use expression::{self,Expression,ExprNode,LocalLabelState};
 
 use attributes::Attribute;
 
+use diagnostics::Diagnostic;
+
 #[derive(Debug)]
 pub enum CompileError {
     ParseError(ParseError),
-    CompileError(instructions::CompileError)
+    // instruction name, and what went wrong with it
+    CompileError(Span, instructions::CompileError),
+    AddressingMode(Span),
+    // an expression that can't be written with its size
+    InvalidSize(Expression),
+    UnexpectedStatement(Statement)
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::CompileError::*;
+        match self {
+            ParseError(e) => write!(f, "{}", e),
+            CompileError(name, instructions::CompileError::Instruction) => write!(f, "unknown instruction {}", name),
+            CompileError(name, instructions::CompileError::AddressMode(c)) => write!(f, "{} can't be used with {:?} addressing", name, c),
+            CompileError(name, instructions::CompileError::WriteError(e)) => write!(f, "couldn't write {}: {}", name, e),
+            AddressingMode(name) => write!(f, "invalid addressing mode for {}", name),
+            InvalidSize(e) => write!(f, "expression {} can't be written with size {:?}", e, e.size),
+            UnexpectedStatement(s) => write!(f, "unexpected {}", s)
+        }
+    }
+}
+
+impl CompileError {
+    pub fn location(&self) -> Option<(Location, u32)> {
+        use self::CompileError::*;
+        match self {
+            ParseError(e) => e.location(),
+            CompileError(name, _) | AddressingMode(name) => name.location(),
+            UnexpectedStatement(Statement::LocalLabel { name, .. }) => name.location(),
+            _ => None
+        }
+    }
+    pub fn diagnostic(&self) -> Diagnostic {
+        match self {
+            CompileError::ParseError(e) => e.diagnostic(),
+            e => Diagnostic::error(e.to_string()).with_span(e.location())
+        }
+    }
 }
 
 #[derive(Debug,Clone)]
@@ -33,13 +76,19 @@ pub struct LabelRef {
     pub offset: usize,
     pub expr: Expression,
     // Enforce that the referenced label is placed in the same bank.
-    pub same_bank: bool
+    pub same_bank: bool,
+    // Jumps and branches go to the program bank, everything else to the data bank
+    pub program_bank: bool,
+    // The instruction and its addressing mode, if the linker picks the operand size
+    pub sizing: Option<(String, AddressingMode)>,
+    // Where the reference was made, for error messages
+    pub location: Option<(Location, u32)>
 }
 
 #[derive(Debug)]
 pub enum CompileData {
     Chunk { label: String, chunk: LabeledChunk },
-    Define { label: String, attrs: Vec<Attribute>, expr: Expression },
+    Define { label: String, attrs: Vec<Attribute>, expr: Expression, location: Option<(Location, u32)> },
     Error(CompileError),
 }
 
@@ -50,7 +99,21 @@ pub struct LabeledChunk {
     pub pending_exprs: Vec<LabelRef>,
     pub attrs: Vec<Attribute>,
     pub diverging: bool,
-    pub bank_hint: Option<u8>
+    // whether there are any instructions, as opposed to just data
+    pub has_code: bool,
+    pub bank_hint: Option<u8>,
+    // set by #[data_bank(..)], otherwise it's assumed to be the chunk's own bank
+    pub data_bank: Option<u8>,
+    // set by #[direct_page(..)]
+    pub direct_page: Option<u16>,
+    // exact SNES address, set by #[pin(..)]
+    pub pinned: Option<u32>,
+    // where the label was defined, none for the code before the first label
+    pub location: Option<(Location, u32)>,
+    // local labels in it, (`loop` or `loop.inner`, offset)
+    pub locals: Vec<(String, usize)>,
+    // where every statement in it starts, (offset, location)
+    pub lines: Vec<(usize, Location)>
 }
 
 impl LabeledChunk {
@@ -58,13 +121,20 @@ impl LabeledChunk {
         Self {
             data: vec![0; len],
             diverging: true,
+            has_code: false,
             attrs: Vec::new(),
             pending_exprs: vec![],
-            bank_hint
+            bank_hint,
+            data_bank: None,
+            direct_page: None,
+            pinned: None,
+            location: None,
+            locals: Vec::new(),
+            lines: Vec::new()
         }
     }
-    pub fn pin(&self, _addr: u16) {
-        // TODO: ability to pin chunks to concrete addresses
+    pub fn pin(&mut self, addr: u32) {
+        self.pinned = Some(addr);
     }
     pub fn get_data(&self) -> &[u8] {
         &*self.data
@@ -79,7 +149,23 @@ impl LabeledChunk {
 
 #[derive(Debug,Default)]
 pub struct CompilerStateInner {
-    pub lls: LocalLabelState
+    pub lls: LocalLabelState,
+    // searched in order for incsrc / incbin, after the working directory
+    pub include_dirs: Vec<PathBuf>,
+    // every file read while compiling, hashed into object files
+    pub sources: Vec<PathBuf>,
+    pub verbosity: u8
+}
+
+impl CompilerStateInner {
+    pub fn find_file(&self, filename: &str) -> PathBuf {
+        let path = Path::new(filename);
+        if path.is_absolute() || path.exists() { return path.to_path_buf(); }
+        self.include_dirs.iter()
+            .map(|c| c.join(path))
+            .find(|c| c.exists())
+            .unwrap_or_else(|| path.to_path_buf())
+    }
 }
 
 #[derive(Debug,Clone,Default)]
@@ -110,7 +196,7 @@ pub struct Compiler {
 #[derive(Debug,Default)]
 struct LocalState {
     chunk: LabeledChunk,
-    local_defines: Vec<(String, Vec<Attribute>, Expression)>,
+    local_defines: Vec<(String, Vec<Attribute>, Expression, Option<(Location, u32)>)>,
     // label name -> offset
     labels: HashMap<ExprNode, usize>,
     // all the places where it should be replaced
@@ -122,25 +208,29 @@ impl Compiler {
         Self { inner: Box::new(inner) as Box<Iterator<Item=_>>, state, extra: Vec::new(), next_attrs: Vec::new(), next_label: Some(SpanData::create("*root".to_string())) }
     }
     pub fn new(filename: &str) -> Result<Self,Box<Error>> {
-        use std::io::{self,prelude::*,BufReader};
+        Self::with_state(filename, CompilerState::default())
+    }
+    pub fn with_state(filename: &str, state: CompilerState) -> Result<Self,Box<Error>> {
+        use std::io::prelude::*;
         use std::fs::File;
-        use lexer;
-        let state = CompilerState::default();
-        let file = match filename {
+        let mut file = match filename {
             "-" => Box::new(io::stdin()) as Box<Read>,
             c => Box::new(File::open(c)?)
         };
-        let file = BufReader::new(file);
-        let lexed = lexer::from_filename(filename.to_string()).unwrap();
+        let mut buf = String::new();
+        file.read_to_string(&mut buf)?;
+        state.borrow_mut().sources.push(PathBuf::from(filename));
+        let lexed = Lexer::new(filename.to_string(), buf.chars().collect::<Vec<_>>().into_iter());
         let inner = Parser::new(lexed, state.clone(), Vec::new());
         Ok(Self::from_iter(inner, state))
-        //Ok(Self { inner, state, extra: Vec::new(), next_attrs: Vec::new(), next_label: Some(SpanData::create("*root".to_string())) })
     }
     // This function calculates all expressions that can be reduced (usually ones with local
     // labels), and if it ends up being a constant, it replaces the part in the chunk with that
     // constant.
     fn merge_labels(&mut self, mut ls: LocalState) -> LabeledChunk {
         let LocalState { mut chunk, pending_exprs, local_defines, mut labels } = ls;
+        // The linker may have to make branches longer, so it needs to know where they go
+        let relax = self.next_attrs.iter().any(|c| if let Attribute::RelaxBranches = c { true } else { false });
         use std::io::{Cursor, Seek, SeekFrom};
         let mut cursor = Cursor::new(&mut chunk.data);
         let mut linker_exprs = Vec::new();
@@ -158,25 +248,32 @@ impl Compiler {
                 ExprNode::Constant(c) => {
                     cursor.seek(SeekFrom::Start(offset as u64)).unwrap();
                     match r.expr.size {
-                        SizeHint::RelByte | SizeHint::RelWord => panic!("This doesn't make any sense."),
                         SizeHint::Byte => cursor.write_u8(c as u8).unwrap(),
                         SizeHint::Word => cursor.write_u16::<LittleEndian>(c as u16).unwrap(),
                         SizeHint::Long => cursor.write_u24::<LittleEndian>(c as u32).unwrap(),
-                        _ => panic!("Weird size?")
+                        // relative branches to a constant don't make any sense
+                        _ => self.extra.push(CompileData::Error(CompileError::InvalidSize(r.expr)))
                     }
                 },
                 ExprNode::LabelOffset(c) => {
                     cursor.seek(SeekFrom::Start(offset as u64)).unwrap();
+                    // from the end of the instruction
+                    let distance = c as i32 - offset as i32 - match r.expr.size {
+                        SizeHint::RelWord => 2,
+                        _ => 1
+                    };
+                    // Branches that don't reach are left to the linker, which reports them (or
+                    // makes them longer)
                     match r.expr.size {
-                        SizeHint::RelByte => cursor.write_i8((c as i32 - offset as i32 - 1) as i8).unwrap(),
-                        SizeHint::RelWord => cursor.write_i16::<LittleEndian>((c as i32 - offset as i32 - 1) as i16).unwrap(),
+                        SizeHint::RelByte if !relax && distance >= -0x80 && distance <= 0x7F => cursor.write_i8(distance as i8).unwrap(),
+                        SizeHint::RelWord if distance >= -0x8000 && distance <= 0x7FFF => cursor.write_i16::<LittleEndian>(distance as i16).unwrap(),
                         _ => linker_exprs.push(r),
                     }
                 },
                 _ => linker_exprs.push(r)
             }
         }
-        for (label, attrs, mut expr) in local_defines.into_iter() {
+        for (label, attrs, mut expr, location) in local_defines.into_iter() {
             expr.each_mut(|c| {
                 // This has to be done because local expansion will fuck it.
                 // TODO: make this work in a more civilized way
@@ -190,15 +287,25 @@ impl Compiler {
                 };
             });
             expr.reduce();
-            self.extra.push(CompileData::Define { label, attrs, expr });
+            self.extra.push(CompileData::Define { label, attrs, expr, location });
         }
         chunk.pending_exprs = linker_exprs;
         chunk
     }
+    fn apply_attrs(chunk: &mut LabeledChunk, attrs: Vec<Attribute>) {
+        for i in &attrs { match i {
+            Attribute::Bank(c) => chunk.bank_hint = Some(*c),
+            Attribute::DataBank(c) => chunk.data_bank = Some(*c),
+            Attribute::DirectPage(c) => chunk.direct_page = Some(*c),
+            Attribute::Pin(c) => chunk.pin(*c),
+            _ => {}
+        } }
+        chunk.attrs = attrs;
+    }
     fn res_next(&mut self) -> Result<Option<CompileData>,CompileError> {
         use self::Statement::*;
         if self.extra.len() > 0 {
-            return Ok(self.extra.pop())
+            return Ok(Some(self.extra.remove(0)))
         }
         let mut ls = LocalState::default();
         loop {
@@ -207,25 +314,33 @@ impl Compiler {
                 return match self.next_label.take() {
                     None => Ok(None),
                     Some(c) => {
-                        let chunk = self.merge_labels(ls);
+                        let mut chunk = self.merge_labels(ls);
+                        let attrs = mem::replace(&mut self.next_attrs, Vec::new());
+                        Self::apply_attrs(&mut chunk, attrs);
+                        if c.length > 0 { chunk.location = Some((c.start, c.length)); }
                         Ok(Some(CompileData::Chunk { label: c.data, chunk }))
                     }
                 }
             };
+            // labels that start a new chunk have its location instead
+            match c {
+                Label { name: Span::Ident(_), .. } | Statement::Define { .. } => {},
+                ref c => if let Some((location, _)) = c.location() {
+                    ls.chunk.lines.push((ls.chunk.data.len(), location));
+                }
+            }
             match c {
                 Statement::Define { label, attrs, expr } => {
-                    ls.local_defines.push((label.as_ident().unwrap().to_string(), attrs, expr));
+                    let location = label.location();
+                    ls.local_defines.push((label.as_ident().unwrap().to_string(), attrs, expr, location));
                 },
                 // Split here
                 Label { name: Span::Ident(mut name), mut attrs } => {
                     let mut chunk = self.merge_labels(ls);
                     mem::swap(self.next_label.as_mut().unwrap(), &mut name);
                     mem::swap(&mut self.next_attrs, &mut attrs);
-                    for i in &attrs { match i {
-                        Attribute::Bank(c) => chunk.bank_hint = Some(*c),
-                        _ => {}
-                    } }
-                    chunk.attrs = attrs;
+                    Self::apply_attrs(&mut chunk, attrs);
+                    if name.length > 0 { chunk.location = Some((name.start, name.length)); }
                     return Ok(Some(CompileData::Chunk { label: name.data, chunk }));
                 },
                 // TODO: move this to the parser? Maybe? It's a bit split rn
@@ -244,14 +359,17 @@ impl Compiler {
                 LocalLabel { depth, name: Span::Ident(c) } => {
                     ls.chunk.diverging = false;
                     let s = self.state.borrow_mut().lls.push_local(depth, c.data);
+                    if let ExprNode::LocalLabel { ref stack } = s {
+                        ls.chunk.locals.push((stack.join("."), ls.chunk.data.len()));
+                    }
                     ls.labels.insert(s, ls.chunk.data.len());
                 },
-                RawData { data, pending_exprs: p } => {
+                RawData { data, pending_exprs: p, location } => {
                     // Executing raw data is not advisable.
                     ls.chunk.diverging = true;
                     use std::io::Write;
                     let len = ls.chunk.data.len();
-                    ls.pending_exprs.extend(p.into_iter().map(|(off, expr)| LabelRef { offset: len+off, expr, same_bank: false }));
+                    ls.pending_exprs.extend(p.into_iter().map(|(off, expr)| LabelRef { offset: len+off, expr, same_bank: false, program_bank: false, sizing: None, location: location.clone() }));
                     ls.chunk.data.write(&data).unwrap();
                 },
                 Instruction { name, size, arg, .. } => {
@@ -271,22 +389,68 @@ impl Compiler {
                     const_only |= s == SizeHint::Implicit && arg.expr.root == ExprNode::Label("A".to_string());
                     let s = s.and_then(arg.expr.size)
                         .and_then(size.0);
-                    if !const_only {
+                    let mut new_expr = if const_only { None } else {
                         let mut new_expr = arg.expr.clone();
                         new_expr.size = s;
-                        ls.pending_exprs.push(LabelRef { offset: ls.chunk.data.len()+1, expr: new_expr, same_bank: true });
+                        Some(new_expr)
+                    };
+                    let mut arg = match AddressingMode::parse(arg, s) {
+                        Ok(c) => c,
+                        Err(_) => {
+                            // Errors are reported after this chunk, compilation goes on
+                            self.extra.push(CompileData::Error(CompileError::AddressingMode(name)));
+                            continue;
+                        }
+                    };
+                    // Labels without a size get the shortest encoding for now, the linker makes
+                    // it longer once it knows the address
+                    let mut sizing = None;
+                    if let (Some(expr), SizeHint::Unspecified) = (new_expr.as_mut(), s) {
+                        let upper = name.as_ident().unwrap().to_uppercase();
+                        let shortest = [SizeHint::Byte, SizeHint::Word, SizeHint::Long].iter()
+                            .filter_map(|&c| arg.resize(c).map(|mode| (c, mode)))
+                            .find(|&(_, mode)| SInstruction::new(&upper, mode).write_to(io::sink()).is_ok());
+                        if let Some((size, mode)) = shortest {
+                            arg = mode;
+                            expr.size = size;
+                            sizing = Some((upper, mode));
+                        }
                     }
-                    let arg = AddressingMode::parse(arg, s).map_err(|_| { print!("wrong addressing mode {:?}", name); panic!() })?;
+                    // Only 16-bit addresses depend on the bank they're used from
+                    let (same_bank, program_bank) = match arg {
+                        AddressingMode::Absolute(_) | AddressingMode::AbsoluteX(_) | AddressingMode::AbsoluteY(_) => {
+                            match &*name.as_ident().unwrap().to_uppercase() {
+                                "JMP" | "JSR" => (true, true),
+                                // pushes the address as a value
+                                "PEA" => (false, false),
+                                _ => (true, false)
+                            }
+                        },
+                        AddressingMode::AbsIndX(_) | AddressingMode::Relative(_) | AddressingMode::RelativeWord(_) => (true, true),
+                        _ => (false, false)
+                    };
+                    // the linker takes care of those
+                    let (same_bank, program_bank) = if sizing.is_some() { (false, false) } else { (same_bank, program_bank) };
                     let instr = SInstruction::new(name.as_ident().unwrap(), arg);
-                    if instr.is_diverging() { ls.chunk.diverging = true; }
-                    instr.write_to(&mut ls.chunk.data).map_err(CompileError::CompileError)?;
+                    let mut buf = Vec::new();
+                    if let Err(e) = instr.write_to(&mut buf) {
+                        self.extra.push(CompileData::Error(CompileError::CompileError(name, e)));
+                        continue;
+                    }
+                    if let Some(expr) = new_expr {
+                        ls.pending_exprs.push(LabelRef { offset: ls.chunk.data.len()+1, expr, same_bank, program_bank, sizing, location: name.location() });
+                    }
+                    // only the last instruction decides whether it falls through
+                    ls.chunk.diverging = instr.is_diverging();
+                    ls.chunk.has_code = true;
+                    ls.chunk.data.extend(buf);
                 },
                 Error(e) => {
-                    println!("{}",e);
-                    panic!("Error occured");
+                    self.extra.push(CompileData::Error(CompileError::ParseError(e)));
                 },
+                Nothing => {},
                 c => {
-                    panic!("unknown statement {:?}", c);
+                    self.extra.push(CompileData::Error(CompileError::UnexpectedStatement(c)));
                 }
             }
         }
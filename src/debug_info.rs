@@ -0,0 +1,45 @@
+// Debug info: which source line every range of the ROM came from, for emulator front-ends and trace
+// tools. One range per line, sorted by address, tab separated:
+//
+//   SNES address, ROM file offset, length (all hex), line, column, file
+//
+// Code generated by a lua script points at the `lua` line that ran it.
+
+use std::io::{self,Write};
+
+use symbols::Symbols;
+
+pub fn write<W: Write>(symbols: &Symbols, mut w: W) -> io::Result<()> {
+    writeln!(w, "; piped debug info")?;
+    writeln!(w, "; address\toffset\tlength\tline\tcolumn\tfile")?;
+    let mut lines = symbols.lines.iter().filter(|c| c.len > 0).collect::<Vec<_>>();
+    lines.sort_by_key(|c| c.address);
+    for c in lines {
+        writeln!(w, "{:06X}\t{:06X}\t{:X}\t{}\t{}\t{}", c.address, c.offset, c.len, c.location.line(), c.location.column(), c.location.file())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use lexer::Location;
+    use symbols::SourceLine;
+    #[test]
+    fn ranges() {
+        let at = |file: &str, line| Location::new(Rc::new(file.to_string()), line, 2, 0);
+        let symbols = Symbols {
+            lines: vec![
+                SourceLine { location: at("main.asm", 3), address: 0x808010, offset: 0x10, len: 3 },
+                SourceLine { location: at("main.asm", 2), address: 0x808000, offset: 0, len: 0 },
+                SourceLine { location: at("inc/data file.asm", 7), address: 0x818000, offset: 0x8000, len: 0x100 }
+            ],
+            ..Default::default()
+        };
+        let mut buf = Vec::new();
+        write(&symbols, &mut buf).unwrap();
+        let text = String::from_utf8(buf).unwrap();
+        assert_eq!(text.lines().skip(2).collect::<Vec<_>>(), vec!["808010\t000010\t3\t3\t2\tmain.asm", "818000\t008000\t100\t7\t2\tinc/data file.asm"]);
+    }
+}
@@ -0,0 +1,150 @@
+// Error and warning reporting, modeled after rustc:
+//
+// error: unknown addressing mode ($12,y)
+//   --> main.asm:12:6
+//    |
+// 12 |     LDA ($12,y)
+//    |         ^^^^^^^
+//    = note: ...
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::{self,Write};
+use std::fs::File;
+use std::io::Read;
+
+use lexer::Location;
+use colors::prelude::*;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Level {
+    Error,
+    Warning,
+    Note
+}
+
+impl Level {
+    fn color(self) -> u8 {
+        match self {
+            Level::Error => 9,
+            Level::Warning => 11,
+            Level::Note => 14
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Level::Error => "error",
+            Level::Warning => "warning",
+            Level::Note => "note"
+        })
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct Diagnostic {
+    pub level: Level,
+    pub message: String,
+    // start and length in columns
+    pub span: Option<(Location, u32)>,
+    pub notes: Vec<String>
+}
+
+// Source files are only read when a diagnostic needs them, and only once
+thread_local! {
+    static SOURCES: RefCell<HashMap<String, Option<Vec<String>>>> = RefCell::new(HashMap::new());
+}
+
+pub fn source_line(file: &str, line: u32) -> Option<String> {
+    SOURCES.with(|c| {
+        let mut c = c.borrow_mut();
+        let lines = c.entry(file.to_string()).or_insert_with(|| {
+            let mut buf = String::new();
+            File::open(file).ok()?.read_to_string(&mut buf).ok()?;
+            Some(buf.lines().map(String::from).collect())
+        });
+        lines.as_ref()?.get(line.checked_sub(1)? as usize).cloned()
+    })
+}
+
+impl Diagnostic {
+    pub fn new(level: Level, message: impl Into<String>) -> Self {
+        Diagnostic { level, message: message.into(), span: None, notes: Vec::new() }
+    }
+    pub fn error(message: impl Into<String>) -> Self {
+        Self::new(Level::Error, message)
+    }
+    pub fn warning(message: impl Into<String>) -> Self {
+        Self::new(Level::Warning, message)
+    }
+    pub fn with_span(mut self, span: Option<(Location, u32)>) -> Self {
+        // Spans without a file (e.g. generated ones) are useless
+        self.span = span.filter(|c| c.0.file() != "");
+        self
+    }
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let level = format!("{}", self.level);
+        writeln!(out, "{}{}", level.pretty() + Color(self.level.color()) + Bold, format!(": {}", self.message).pretty() + Bold).unwrap();
+        let gutter = self.span.as_ref().map(|c| c.0.line().to_string().len()).unwrap_or(0);
+        let bar = |out: &mut String, num: &str| write!(out, "{}", format!("{: >w$} |", num, w = gutter).pretty() + Color(12) + Bold).unwrap();
+        if let Some((ref loc, length)) = self.span {
+            writeln!(out, "{}{}:{}:{}", format!("{: >w$}--> ", "", w = gutter).pretty() + Color(12) + Bold, loc.file(), loc.line(), loc.column()).unwrap();
+            if let Some(line) = source_line(loc.file(), loc.line()) {
+                // Tabs are expanded so the carets line up
+                let col = loc.column().saturating_sub(1) as usize;
+                let prefix = line.chars().take(col).map(|c| if c == '\t' { "    ".to_string() } else { " ".to_string() }).collect::<String>();
+                bar(&mut out, "");
+                out.push('\n');
+                bar(&mut out, &loc.line().to_string());
+                writeln!(out, " {}", line.replace('\t', "    ")).unwrap();
+                bar(&mut out, "");
+                let carets = "^".repeat(length.max(1) as usize);
+                writeln!(out, " {}{}", prefix, carets.pretty() + Color(self.level.color()) + Bold).unwrap();
+            }
+        }
+        for note in self.notes.iter() {
+            writeln!(out, "{}{} {}", format!("{: >w$} = ", "", w = gutter).pretty() + Color(12) + Bold, "note:".pretty() + Bold, note).unwrap();
+        }
+        out
+    }
+    pub fn emit(&self) {
+        eprint!("{}", self.render());
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.render())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use colors;
+    use lexer::{Lexer,Span};
+    #[test]
+    fn render() {
+        colors::set_enabled(false);
+        let span = Lexer::new("<test>".to_string(), "LDA ($12,y)".chars()).nth(2).unwrap();
+        SOURCES.with(|c| c.borrow_mut().insert("<test>".to_string(), Some(vec!["LDA ($12,y)".to_string()])));
+        let d = Diagnostic::error("unknown addressing mode")
+            .with_span(span.location())
+            .with_note("try this");
+        assert_eq!(d.render(), "\
+error: unknown addressing mode
+ --> <test>:1:5
+  |
+1 | LDA ($12,y)
+  |     ^
+  = note: try this
+");
+    }
+}
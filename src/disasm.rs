@@ -0,0 +1,203 @@
+// Simple linear disassembler.
+// The opcode table isn't written out by hand, it's recovered by running every mnemonic and
+// addressing mode through the assembler, so the two can't disagree.
+
+use std::fmt::Write;
+
+use addrmodes::AddressingMode;
+use instructions::{Instruction, MNEMONICS};
+
+// Instructions whose immediate operand size depends on the m / x flags
+const M_IMMEDIATE: &[&str] = &["ADC", "AND", "BIT", "CMP", "EOR", "LDA", "ORA", "SBC"];
+const X_IMMEDIATE: &[&str] = &["CPX", "CPY", "LDX", "LDY"];
+
+// Order matters: on a tie, the earlier mode is used for display
+fn sample_modes() -> Vec<AddressingMode> {
+    use addrmodes::AddressingMode::*;
+    vec![
+        Implied, DirectPage(0), DPX(0), DPY(0), DPInd(0), DPIndX(0), DPIndY(0), DPIndLong(0),
+        DPIndLongY(0), Stack(0), StackY(0), Relative(0), RelativeWord(0), Absolute(0),
+        AbsoluteX(0), AbsoluteY(0), AbsInd(0), AbsIndX(0), AbsIndLong(0), AbsLong(0), AbsLongX(0),
+        BlockMove(0, 0), Immediate(0), ImmediateWord(0)
+    ]
+}
+
+pub struct OpcodeTable {
+    ops: Vec<Option<(&'static str, AddressingMode)>>
+}
+
+impl OpcodeTable {
+    pub fn new() -> Self {
+        use addrmodes::AddressingMode::*;
+        let mut ops: Vec<Option<(&'static str, AddressingMode, usize)>> = vec![None; 256];
+        for name in MNEMONICS {
+            for mode in sample_modes() {
+                let mut buf = Vec::new();
+                if Instruction::new(name, mode).write_to(&mut buf).is_err() || buf.is_empty() { continue; }
+                let entry = &mut ops[buf[0] as usize];
+                let replace = match (*entry, mode) {
+                    (None, _) => true,
+                    // LDA #$12 and LDA #$1234 share an opcode, the flags decide
+                    (Some((n, Immediate(_), _)), ImmediateWord(_)) if n == *name => false,
+                    (Some((n, ImmediateWord(_), _)), Immediate(_)) if n == *name => true,
+                    // prefer the longer encoding (BRK #$00 over BRK)
+                    (Some((_, _, len)), _) => buf.len() > len
+                };
+                if replace { *entry = Some((name, mode, buf.len())); }
+            }
+        }
+        OpcodeTable { ops: ops.into_iter().map(|c| c.map(|(n, m, _)| (n, m))).collect() }
+    }
+    pub fn get(&self, opcode: u8) -> Option<(&'static str, AddressingMode)> {
+        self.ops[opcode as usize]
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Flags {
+    pub m8: bool,
+    pub x8: bool
+}
+
+impl Default for Flags {
+    fn default() -> Self {
+        Flags { m8: true, x8: true }
+    }
+}
+
+pub struct Decoded {
+    pub name: &'static str,
+    pub mode: AddressingMode,
+    pub bytes: Vec<u8>
+}
+
+// Decodes a single instruction and updates the flags for REP / SEP
+pub fn decode(table: &OpcodeTable, data: &[u8], flags: &mut Flags) -> Option<Decoded> {
+    use addrmodes::AddressingMode::*;
+    let (name, mode) = table.get(*data.get(0)?)?;
+    let byte = |i: usize| data.get(i).map(|c| *c as u32);
+    let word = || Some(byte(1)? | byte(2)? << 8);
+    let long = || Some(word()? | byte(3)? << 16);
+    let (mode, len) = match mode {
+        Implied => (Implied, 1),
+        Immediate(_) | ImmediateWord(_) => {
+            let wide = match mode { ImmediateWord(_) => true, _ => false }
+                || (M_IMMEDIATE.contains(&name) && !flags.m8)
+                || (X_IMMEDIATE.contains(&name) && !flags.x8);
+            if wide { (ImmediateWord(word()? as u16), 3) } else { (Immediate(byte(1)? as u8), 2) }
+        },
+        DirectPage(_) => (DirectPage(byte(1)? as u8), 2),
+        DPX(_) => (DPX(byte(1)? as u8), 2),
+        DPY(_) => (DPY(byte(1)? as u8), 2),
+        DPInd(_) => (DPInd(byte(1)? as u8), 2),
+        DPIndX(_) => (DPIndX(byte(1)? as u8), 2),
+        DPIndY(_) => (DPIndY(byte(1)? as u8), 2),
+        DPIndLong(_) => (DPIndLong(byte(1)? as u8), 2),
+        DPIndLongY(_) => (DPIndLongY(byte(1)? as u8), 2),
+        Stack(_) => (Stack(byte(1)? as u8), 2),
+        StackY(_) => (StackY(byte(1)? as u8), 2),
+        Relative(_) => (Relative(byte(1)? as u8 as i8), 2),
+        RelativeWord(_) => (RelativeWord(word()? as u16 as i16), 3),
+        Absolute(_) => (Absolute(word()? as u16), 3),
+        AbsoluteX(_) => (AbsoluteX(word()? as u16), 3),
+        AbsoluteY(_) => (AbsoluteY(word()? as u16), 3),
+        AbsInd(_) => (AbsInd(word()? as u16), 3),
+        AbsIndX(_) => (AbsIndX(word()? as u16), 3),
+        AbsIndLong(_) => (AbsIndLong(word()? as u16), 3),
+        AbsLong(_) => (AbsLong(long()?), 4),
+        AbsLongX(_) => (AbsLongX(long()?), 4),
+        BlockMove(_, _) => (BlockMove(byte(1)? as u8, byte(2)? as u8), 3)
+    };
+    match (name, mode) {
+        ("REP", Immediate(c)) => {
+            if c & 0x20 != 0 { flags.m8 = false; }
+            if c & 0x10 != 0 { flags.x8 = false; }
+        },
+        ("SEP", Immediate(c)) => {
+            if c & 0x20 != 0 { flags.m8 = true; }
+            if c & 0x10 != 0 { flags.x8 = true; }
+        },
+        _ => {}
+    }
+    Some(Decoded { name, mode, bytes: data[..len].to_vec() })
+}
+
+// Formats the operand the same way the parser would read it back
+pub fn operand(mode: AddressingMode, pc: u32) -> String {
+    use addrmodes::AddressingMode::*;
+    let branch = |off: i32, len: i32| (pc & 0xFF0000) | ((pc as i32 + len + off) as u32 & 0xFFFF);
+    match mode {
+        Implied => String::new(),
+        Immediate(c) => format!("#${:02X}", c),
+        ImmediateWord(c) => format!("#${:04X}", c),
+        DirectPage(c) => format!("${:02X}", c),
+        DPX(c) => format!("${:02X},x", c),
+        DPY(c) => format!("${:02X},y", c),
+        DPInd(c) => format!("(${:02X})", c),
+        DPIndX(c) => format!("(${:02X},x)", c),
+        DPIndY(c) => format!("(${:02X}),y", c),
+        DPIndLong(c) => format!("[${:02X}]", c),
+        DPIndLongY(c) => format!("[${:02X}],y", c),
+        Stack(c) => format!("${:02X},s", c),
+        StackY(c) => format!("(${:02X},s),y", c),
+        Absolute(c) => format!("${:04X}", c),
+        AbsoluteX(c) => format!("${:04X},x", c),
+        AbsoluteY(c) => format!("${:04X},y", c),
+        AbsInd(c) => format!("(${:04X})", c),
+        AbsIndX(c) => format!("(${:04X},x)", c),
+        AbsIndLong(c) => format!("[${:04X}]", c),
+        AbsLong(c) => format!("${:06X}", c),
+        AbsLongX(c) => format!("${:06X},x", c),
+        Relative(c) => format!("${:06X}", branch(c as i32, 2)),
+        RelativeWord(c) => format!("${:06X}", branch(c as i32, 3)),
+        BlockMove(c1, c2) => format!("#${:02X},#${:02X}", c1, c2)
+    }
+}
+
+// Disassembles `count` instructions starting at SNES address `start`.
+// `offset` translates a SNES address into an offset into `rom`.
+pub fn disassemble(rom: &[u8], start: u32, count: usize, offset: impl Fn(u32) -> usize) -> String {
+    let table = OpcodeTable::new();
+    let mut flags = Flags::default();
+    let mut out = String::new();
+    let mut pc = start;
+    for _ in 0..count {
+        let data = rom.get(offset(pc)..).unwrap_or(&[]);
+        let d = match decode(&table, data, &mut flags) {
+            Some(c) => c,
+            None => {
+                if let Some(c) = data.get(0) {
+                    writeln!(out, "${:06X}  {:02X}           db ${:02X}", pc, c, c).unwrap();
+                    pc += 1;
+                    continue;
+                }
+                break;
+            }
+        };
+        let bytes = d.bytes.iter().map(|c| format!("{:02X}", c)).collect::<Vec<_>>().join(" ");
+        let line = format!("${:06X}  {: <12} {} {}", pc, bytes, d.name, operand(d.mode, pc));
+        writeln!(out, "{}", line.trim_right()).unwrap();
+        pc += d.bytes.len() as u32;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn roundtrip() {
+        let table = OpcodeTable::new();
+        let mut flags = Flags::default();
+        // SEI : REP #$20 : LDA #$1234 : JML $008000 : BNE -2
+        let code = [0x78, 0xC2, 0x20, 0xA9, 0x34, 0x12, 0x5C, 0x00, 0x80, 0x00, 0xD0, 0xFE];
+        let mut pos = 0;
+        let mut out = Vec::new();
+        while pos < code.len() {
+            let d = decode(&table, &code[pos..], &mut flags).unwrap();
+            out.push(format!("{} {}", d.name, operand(d.mode, 0x8000 + pos as u32)).trim().to_string());
+            pos += d.bytes.len();
+        }
+        assert_eq!(out, vec!["SEI", "REP #$20", "LDA #$1234", "JML $008000", "BNE $00800A"]);
+    }
+}
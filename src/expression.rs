@@ -1,6 +1,6 @@
 // Expression evaluator
 // Supposed to support all sorts of complex math, functions, and even inline lua scripts.
-use lexer::Span;
+use lexer::{Span,Location};
 use std::fmt;
 use instructions::SizeHint;
 pub use lls::LocalLabelState;
@@ -288,6 +288,28 @@ pub enum ExprError {
     EOF(Span)
 }
 
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ExprError::*;
+        match self {
+            InvalidOperand(s) => write!(f, "invalid operand {} in expression", s),
+            InvalidAfterDot(s) => write!(f, "expected a local label name after '.', found {}", s),
+            Other => write!(f, "invalid expression"),
+            EOF(_) => write!(f, "unexpected end of expression")
+        }
+    }
+}
+
+impl ExprError {
+    pub fn location(&self) -> Option<(Location, u32)> {
+        use self::ExprError::*;
+        match self {
+            InvalidOperand(s) | InvalidAfterDot(s) | EOF(s) => s.location(),
+            Other => None
+        }
+    }
+}
+
 use std::option::NoneError;
 impl From<NoneError> for ExprError {
     fn from(_: NoneError) -> Self {
@@ -0,0 +1,184 @@
+// The internal header at $00FFC0, configured through global attributes like #![title("..")]
+
+use attributes::{Attribute,set_once};
+use compiler::{LabeledChunk,LabelRef};
+use expression::{Expression,ExprNode};
+use instructions::SizeHint;
+use mapper::Mapper;
+
+// Everything is optional so conflicting attributes can be found, the defaults are in `chunk`
+#[derive(Debug,Clone,Default)]
+pub struct Header {
+    pub title: Option<String>,
+    pub region: Option<u8>,
+    pub maker: Option<u8>,
+    pub version: Option<u8>,
+    // log2 of the size in KiB
+    pub sram_size: Option<u8>,
+    pub chipset: Option<u8>
+}
+
+impl Header {
+    // Returns the name of the field if it was already set to something else
+    pub fn apply(&mut self, attr: &Attribute) -> Result<(), &'static str> {
+        match attr {
+            Attribute::Title(c) => set_once(&mut self.title, c, "title"),
+            Attribute::Region(c) => set_once(&mut self.region, c, "region"),
+            Attribute::Maker(c) => set_once(&mut self.maker, c, "maker"),
+            Attribute::Version(c) => set_once(&mut self.version, c, "version"),
+            Attribute::Sram(c) => set_once(&mut self.sram_size, c, "sram"),
+            Attribute::Chipset(c) => set_once(&mut self.chipset, c, "chipset"),
+            _ => Ok(())
+        }
+    }
+    // An ordinary chunk pinned to $00FFC0, the vectors are filled in by the linker like any other
+    // label reference. The ROM size and checksum can only be written by `finish`.
+    pub fn chunk(&self, mapper: Mapper) -> LabeledChunk {
+        let mut chunk = LabeledChunk::default();
+        chunk.pin(0x00FFC0);
+        chunk.diverging = true;
+        let mut title = self.title.as_ref().map(|c| &**c).unwrap_or("SUPER MARIOWORLD").as_bytes().to_vec();
+        title.resize(21, b' ');
+        chunk.data.extend_from_slice(&title);
+        chunk.data.extend_from_slice(&[
+            mapper.map_mode(),
+            self.chipset.unwrap_or(mapper.cartridge_type()),
+            0x00,   // ROM size
+            self.sram_size.unwrap_or(0x01),
+            self.region.unwrap_or(0x01),
+            self.maker.unwrap_or(0x01),
+            self.version.unwrap_or(0x00),
+            0xFF, 0xFF,     // checksum complement
+            0x00, 0x00      // checksum
+        ]);
+        for name in VECTORS.iter() {
+            if let Some(name) = name {
+                chunk.pending_exprs.push(vector_ref(name, chunk.data.len()));
+            }
+            chunk.data.extend_from_slice(&[0xFF, 0xFF]);
+        }
+        chunk
+    }
+}
+
+// The vectors at $00FFE0, by the name of the attribute that sets them
+const VECTORS: [Option<&str>; 16] = [
+    // NATIVE
+    None, None,
+    None,           // COP enable
+    Some("BRK"),
+    None,           // ABORT
+    Some("NMI"),
+    None,           // RESET (unused)
+    Some("IRQ"),
+    // EMULATION
+    None, None,
+    None,           // COP enable
+    None,           // unused
+    None,           // ABORT
+    None,           // NMI
+    Some("Start"),  // RESET (execution begins here)
+    None            // IRQ
+];
+
+fn vector_ref(name: &str, offset: usize) -> LabelRef {
+    LabelRef {
+        offset,
+        expr: Expression { root: ExprNode::Label(format!("*{}", name)), size: SizeHint::Word },
+        same_bank: false,
+        program_bank: false,
+        sizing: None,
+        location: None
+    }
+}
+
+// When patching a ROM that already has a header, only the vectors set in the source are written,
+// each as its own pinned chunk
+pub fn vector_chunks(names: &[&str]) -> Vec<(String, LabeledChunk)> {
+    VECTORS.iter().enumerate()
+        .filter_map(|(i, c)| c.filter(|c| names.contains(c)).map(|c| (i, c)))
+        .map(|(i, name)| {
+            let mut chunk = LabeledChunk::padding(2, None);
+            chunk.data = vec![0xFF, 0xFF];
+            chunk.pin(0x00FFE0 + i as u32 * 2);
+            chunk.pending_exprs.push(vector_ref(name, 0));
+            (format!("*{} vector", name), chunk)
+        })
+        .collect()
+}
+
+// Writes the ROM size and checksum, has to run on the finished ROM
+pub fn finish(rom: &mut [u8], header_offset: usize) {
+    rom[header_offset + 0x17] = rom_size_code(rom.len());
+    fix_checksum(rom, header_offset);
+}
+
+// log2 of the size in KiB, rounded up
+pub fn rom_size_code(size: usize) -> u8 {
+    let mut code = 0;
+    while 0x400 << code < size { code += 1; }
+    code
+}
+
+// Rounds up to something a cartridge could have: a power of two, or two of them added together
+pub fn cartridge_size(size: usize) -> usize {
+    let first = size.next_power_of_two();
+    if first == size { return size; }
+    let first = first / 2;
+    first + (size - first).next_power_of_two()
+}
+
+// Sizes that aren't a power of two are summed as if the last part was repeated until it's as big
+// as the first one, which is also how the SNES sees them
+fn mirrored_sum(rom: &[u8]) -> (u16, usize) {
+    let sum = |c: &[u8]| c.iter().fold(0u16, |a, c| a.wrapping_add(*c as u16));
+    let first = rom.len().next_power_of_two();
+    if first == rom.len() { return (sum(rom), rom.len()); }
+    let first = first / 2;
+    let (rest, rest_len) = mirrored_sum(&rom[first..]);
+    (sum(&rom[..first]).wrapping_add(rest.wrapping_mul((first / rest_len) as u16)), first * 2)
+}
+
+pub fn checksum(rom: &[u8]) -> u16 {
+    mirrored_sum(rom).0
+}
+
+// Has to run on the finished ROM. The checksum and its complement always add up to the same
+// bytes, so they can be filled in after summing with placeholders.
+pub fn fix_checksum(rom: &mut [u8], header_offset: usize) {
+    let pos = header_offset + 0x1C;
+    rom[pos..pos + 4].copy_from_slice(&[0xFF, 0xFF, 0x00, 0x00]);
+    let sum = checksum(rom);
+    rom[pos..pos + 4].copy_from_slice(&[!sum as u8, (!sum >> 8) as u8, sum as u8, (sum >> 8) as u8]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn checksum() {
+        let mut rom = vec![0; 0x8000];
+        let mut header = Header::default();
+        header.title = Some("TEST".to_string());
+        let chunk = header.chunk(Mapper::LoRom);
+        assert_eq!(chunk.data.len(), 0x40);
+        assert_eq!(chunk.pending_exprs.iter().map(|c| c.offset).collect::<Vec<_>>(), vec![0x26, 0x2A, 0x2E, 0x3C]);
+        rom[0x7FC0..].copy_from_slice(&chunk.data);
+        rom[0] = 0x12;
+        finish(&mut rom, 0x7FC0);
+        assert_eq!(&rom[0x7FC0..0x7FC5], b"TEST ");
+        assert_eq!(rom[0x7FD7], 0x05);
+        let sum = rom[0x7FDE] as u16 | (rom[0x7FDF] as u16) << 8;
+        let complement = rom[0x7FDC] as u16 | (rom[0x7FDD] as u16) << 8;
+        assert_eq!(sum ^ complement, 0xFFFF);
+        assert_eq!(super::checksum(&rom), sum);
+        assert_eq!(rom_size_code(0x100000), 0x0A);
+        assert_eq!(rom_size_code(0x300000), 0x0C);
+        assert_eq!(cartridge_size(0x8000), 0x8000);
+        assert_eq!(cartridge_size(0x290000), 0x300000);
+        assert_eq!(cartridge_size(0x410000), 0x410000);
+        let mut rom = vec![1; 0x30];
+        rom[0x20..].iter_mut().for_each(|c| *c = 2);
+        assert_eq!(super::checksum(&rom), 0x20 + 0x10 * 2 * 2);
+    }
+}
@@ -112,6 +112,25 @@ pub fn size_hint(instr: &str/*, context: CompilerContext*/) -> SizeHint {
         _ => Unspecified
     }
 }
+
+// Every mnemonic `Instruction::write_to` knows about. When two of them share an opcode, the first
+// one wins in the disassembler (hence JML before JMP).
+pub const MNEMONICS: &[&str] = &[
+    "ADC", "SBC", "CMP", "CPX", "CPY",
+    "DEC", "DEX", "DEY", "INC", "INX", "INY",
+    "AND", "EOR", "ORA", "BIT", "TRB", "TSB", "ASL", "LSR", "ROL", "ROR",
+    "BPL", "BMI", "BVC", "BVS", "BRA", "BCC", "BNE", "BCS", "BEQ", "BRL",
+    "JML", "JMP", "JSL", "JSR", "RTL", "RTS",
+    "BRK", "COP", "RTI", "STP", "WAI",
+    "CLC", "CLD", "CLI", "CLV", "SEC", "SED", "SEI", "REP", "SEP",
+    "STZ", "STA", "STX", "STY", "LDA", "LDX", "LDY",
+    "MVN", "MVP", "NOP", "WDM",
+    "PEA", "PEI", "PER", "PHA", "PHX", "PHY", "PLA", "PLX", "PLY",
+    "PHB", "PHD", "PHK", "PHP", "PLB", "PLD", "PLP",
+    "TAX", "TAY", "TSX", "TXA", "TXS", "TXY", "TYA", "TYX",
+    "TCD", "TCS", "TDC", "TSC", "XBA", "XCE"
+];
+
 // TODO: make an enum
 pub struct Instruction {
     name: String,
@@ -0,0 +1,160 @@
+// IPS patches: "PATCH", then records of a 24-bit offset, a 16-bit length and the data, then "EOF".
+// A record with length 0 is RLE instead, a 16-bit count and the byte to repeat.
+// Everything is big endian.
+
+use std::io::{self,Write};
+
+use byteorder::{BigEndian,WriteBytesExt};
+
+// Records can't start past 16 MiB
+const MAX_OFFSET: usize = 0xFFFFFF;
+// A record at this offset reads as the "EOF" at the end of the patch
+const EOF: usize = 0x454F46;
+const MAX_LEN: usize = 0xFFFF;
+// An RLE record plus the header of the record after it, shorter runs are cheaper as plain data
+const MIN_RUN: usize = 8 + 5;
+// Unchanged bytes between two changes that are still cheaper to repeat than a new record header
+const MAX_GAP: usize = 5;
+
+// Writes a patch that turns `base` into `rom`. Everything past the end of `base` is written, so the
+// patched ROM has the right size even if it ends in zeros.
+pub fn write<W: Write>(base: &[u8], rom: &[u8], mut w: W) -> io::Result<()> {
+    let mut patch = Patch { rom, w: &mut w };
+    patch.w.write_all(b"PATCH")?;
+    let changed = |i: usize| base.get(i) != Some(&rom[i]);
+    let mut pos = 0;
+    while pos < rom.len() {
+        if !changed(pos) { pos += 1; continue; }
+        // extend over small gaps of unchanged bytes
+        let start = pos;
+        let mut end = pos + 1;
+        while end < rom.len() {
+            if changed(end) {
+                end += 1;
+            } else if (end..(end + MAX_GAP + 1).min(rom.len())).any(&changed) {
+                end += 1;
+            } else {
+                break;
+            }
+        }
+        patch.range(start, end)?;
+        pos = end;
+    }
+    patch.w.write_all(b"EOF")
+}
+
+struct Patch<'a, W: 'a> {
+    rom: &'a [u8],
+    w: &'a mut W
+}
+
+impl<'a, W: Write> Patch<'a, W> {
+    // Splits a changed range into plain and RLE records
+    fn range(&mut self, start: usize, end: usize) -> io::Result<()> {
+        let mut plain = start;
+        let mut pos = start;
+        while pos < end {
+            let byte = self.rom[pos];
+            let run = self.rom[pos..end].iter().take_while(|c| **c == byte).count();
+            if run >= MIN_RUN {
+                self.plain(plain, pos)?;
+                self.rle(pos, run, byte)?;
+                pos += run;
+                plain = pos;
+            } else {
+                pos += run;
+            }
+        }
+        self.plain(plain, end)
+    }
+    fn plain(&mut self, mut start: usize, end: usize) -> io::Result<()> {
+        while start < end {
+            // the byte before it is written again instead
+            if start == EOF { start -= 1; }
+            let len = (end - start).min(MAX_LEN);
+            self.header(start)?;
+            self.w.write_u16::<BigEndian>(len as u16)?;
+            self.w.write_all(&self.rom[start..start + len])?;
+            start += len;
+        }
+        Ok(())
+    }
+    fn rle(&mut self, mut start: usize, mut len: usize, byte: u8) -> io::Result<()> {
+        while len > 0 {
+            if start == EOF {
+                self.plain(start, start + 1)?;
+                start += 1;
+                len -= 1;
+                continue;
+            }
+            let count = len.min(MAX_LEN);
+            self.header(start)?;
+            self.w.write_u16::<BigEndian>(0)?;
+            self.w.write_u16::<BigEndian>(count as u16)?;
+            self.w.write_u8(byte)?;
+            start += count;
+            len -= count;
+        }
+        Ok(())
+    }
+    fn header(&mut self, offset: usize) -> io::Result<()> {
+        if offset > MAX_OFFSET {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("IPS patches can't change anything past 16 MiB (offset ${:X})", offset)));
+        }
+        self.w.write_u24::<BigEndian>(offset as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn apply(base: &[u8], patch: &[u8]) -> Vec<u8> {
+        let mut rom = base.to_vec();
+        assert_eq!(&patch[..5], b"PATCH");
+        let mut pos = 5;
+        let num = |c: &[u8]| c.iter().fold(0, |a, c| a << 8 | *c as usize);
+        while &patch[pos..pos + 3] != b"EOF" {
+            let offset = num(&patch[pos..pos + 3]);
+            assert_ne!(offset, EOF);
+            let len = num(&patch[pos + 3..pos + 5]);
+            let data = if len == 0 {
+                let data = vec![patch[pos + 7]; num(&patch[pos + 5..pos + 7])];
+                pos += 8;
+                data
+            } else {
+                pos += 5 + len;
+                patch[pos - len..pos].to_vec()
+            };
+            if rom.len() < offset + data.len() { rom.resize(offset + data.len(), 0); }
+            rom[offset..offset + data.len()].copy_from_slice(&data);
+        }
+        assert_eq!(pos + 3, patch.len());
+        rom
+    }
+    #[test]
+    fn round_trip() {
+        let base = (0..0x460000).map(|c| (c * 13 >> 3) as u8).collect::<Vec<_>>();
+        let mut rom = base.clone();
+        rom[0x10] ^= 1;
+        rom[0x14] ^= 1;
+        for c in rom[0x1000..0x3000].iter_mut() { *c = 0xFF; }
+        rom[EOF] ^= 1;
+        rom.resize(0x480000, 0);
+        let mut patch = Vec::new();
+        write(&base, &rom, &mut patch).unwrap();
+        assert_eq!(apply(&base, &patch), rom);
+        // the fill and the expansion are RLE
+        assert!(patch.len() < 0x100);
+        // a run starting right at "EOF"
+        let mut rom = base.clone();
+        for c in rom[EOF..EOF + 0x100].iter_mut() { *c = 0xAA; }
+        let mut patch = Vec::new();
+        write(&base, &rom, &mut patch).unwrap();
+        assert_eq!(apply(&base, &patch), rom);
+        let mut big = base.clone();
+        big.resize(0x1000010, 0);
+        big[0x100000F] = 1;
+        assert!(write(&base, &big, io::sink()).is_err());
+    }
+}
@@ -13,6 +13,24 @@ pub struct Location {
     file: Rc<String>
 }
 
+impl Location {
+    pub fn new(file: Rc<String>, line: u32, column: u32, byte: u32) -> Self {
+        Location { line, column, byte, file }
+    }
+    pub fn line(&self) -> u32 {
+        self.line
+    }
+    pub fn column(&self) -> u32 {
+        self.column
+    }
+    pub fn byte(&self) -> u32 {
+        self.byte
+    }
+    pub fn file(&self) -> &str {
+        &self.file
+    }
+}
+
 impl Display for Location {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}:{} in {}", self.line, self.column, self.file)
@@ -166,6 +184,40 @@ impl Display for Span {
 }
 
 impl Span {
+    // Where the span starts and how many columns it covers
+    pub fn location(&self) -> Option<(Location, u32)> {
+        use self::Span::*;
+        match self {
+            Ident(s) | String(s) => Some((s.start.clone(), s.length)),
+            Symbol(_, s) => Some((s.start.clone(), s.length)),
+            Number(s) | Byte(s) | Word(s) | Long(s) => Some((s.start.clone(), s.length)),
+            PosLabel(s) | NegLabel(s) => Some((s.start.clone(), s.length)),
+            NumberError(s) => Some((s.start.clone(), s.length)),
+            Successive(c) => {
+                let mut iter = c.iter().filter_map(|c| c.location());
+                let (start, length) = iter.next()?;
+                let end = iter.filter(|c| c.0.line == start.line && c.0.file == start.file)
+                    .map(|(c, l)| c.column + l)
+                    .max()
+                    .unwrap_or(start.column + length);
+                Some((start.clone(), end - start.column))
+            },
+            Whitespace | LineBreak | Empty => None
+        }
+    }
+    // Makes the span point somewhere else, keeping its contents
+    pub fn relocate(&mut self, (location, len): (Location, u32)) {
+        use self::Span::*;
+        match self {
+            Ident(s) | String(s) => { s.start = location; s.length = len; },
+            Symbol(_, s) => { s.start = location; s.length = len; },
+            Number(s) | Byte(s) | Word(s) | Long(s) => { s.start = location; s.length = len; },
+            PosLabel(s) | NegLabel(s) => { s.start = location; s.length = len; },
+            NumberError(s) => { s.start = location; s.length = len; },
+            Successive(c) => for i in c.iter_mut() { i.relocate((location.clone(), len)) },
+            Whitespace | LineBreak | Empty => {}
+        }
+    }
     pub fn as_ident(&self) -> Option<&str> {
         if let Span::Ident(ref s) = self {
             Some(&s.data)
@@ -8,9 +8,10 @@ extern crate linked_hash_map;
 
 use std::fs::File;
 use std::error::Error;
-use std::io::BufWriter;
+use std::io::{self,BufWriter,Read,Write};
 use std::env;
 use std::process;
+use std::path::Path;
 
 
 pub mod lexer;
@@ -24,16 +25,253 @@ mod attributes;
 mod colors;
 mod n_peek;
 mod lls;
+pub mod cli;
+mod disasm;
+mod mapper;
+mod header;
+pub mod diagnostics;
+pub mod object;
+mod ips;
+mod bps;
+mod symbols;
+mod listing;
+mod debug_info;
 
-use compiler::Compiler;
+use compiler::{Compiler,CompilerState,CompileData};
+use cli::{Command,Options,OutputFormat};
+use expression::{Expression,LocalLabelState};
+use lexer::{Lexer,Span};
+use n_peek::NPeekable;
+use mapper::Mapper;
+use object::Object;
+use symbols::Symbols;
+use diagnostics::Diagnostic;
 
 pub fn run() -> Result<(),Box<Error>> {
-    let args = env::args().collect::<Vec<_>>();
-    // TODO: arg parsing
-    let filename = args.get(1).ok_or("arguments pls")?;
-    let output_filename = args.get(2).map(|c| &**c).unwrap_or("out.sfc");
-    let compiled = Compiler::new(filename)?;
-    let mut output = BufWriter::new(File::create(output_filename)?);
-    linker::link(&mut output, compiled);
+    colors::init();
+    let opts = Options::parse(env::args().skip(1))?;
+    if opts.help {
+        println!("{}", cli::USAGE);
+        return Ok(());
+    }
+    match opts.command {
+        Command::Build => build(&opts),
+        Command::Check => check(&opts),
+        Command::Compile => compile_object(&opts),
+        Command::Link => link(&opts),
+        Command::Disasm => disassemble(&opts)
+    }
+}
+
+// -D Name=value, parsed like the right hand side of a `define`
+fn parse_define(name: &str, value: &str) -> Result<CompileData,Box<Error>> {
+    let lexed = Lexer::new("<command line>".to_string(), value.chars())
+        .filter(|c| !c.is_whitespace());
+    let expr = Expression::parse(&mut NPeekable::new(lexed), &mut LocalLabelState::default())
+        .map_err(|e| format!("invalid value for define {}: {:?}", name, e))?;
+    Ok(CompileData::Define { label: name.to_string(), attrs: Vec::new(), expr, location: None })
+}
+
+fn defines(opts: &Options) -> Result<Vec<CompileData>,Box<Error>> {
+    opts.defines.iter()
+        .map(|(name, value)| parse_define(name, value))
+        .collect()
+}
+
+fn compile(opts: &Options, state: CompilerState) -> Result<impl Iterator<Item=CompileData>,Box<Error>> {
+    {
+        let mut inner = state.borrow_mut();
+        inner.include_dirs = opts.include_dirs.clone();
+        inner.verbosity = opts.verbosity;
+    }
+    let compiled = Compiler::with_state(&opts.inputs[0], state)?;
+    // command line defines come last so they override the ones in the source
+    Ok(compiled.chain(defines(opts)?))
+}
+
+// Prints every error, then fails with a summary
+fn report<T>(res: Result<T,linker::LinkErrors>) -> Result<T,Box<Error>> {
+    if let Err(ref errors) = res {
+        for e in errors.0.iter() {
+            e.diagnostic().emit();
+        }
+    }
+    Ok(res?)
+}
+
+fn mapper(opts: &Options) -> Result<Option<Mapper>,Box<Error>> {
+    match opts.mapper {
+        Some(ref c) => Ok(Some(Mapper::parse(c).ok_or_else(|| format!("unsupported mapper: {}", c))?)),
+        None => Ok(None)
+    }
+}
+
+// Reads a ROM, without the copier header if there is one
+fn read_rom(path: &str) -> Result<Vec<u8>,Box<Error>> {
+    let mut rom = Vec::new();
+    File::open(path)
+        .and_then(|mut c| c.read_to_end(&mut rom))
+        .map_err(|e| format!("{}: {}", path, e))?;
+    if rom.len() % 0x8000 == 0x200 { rom.drain(..0x200); }
+    Ok(rom)
+}
+
+// The base ROM, with --base-patch applied
+fn base_rom(opts: &Options) -> Result<Option<Vec<u8>>,Box<Error>> {
+    let rom = match opts.base {
+        Some(ref c) => read_rom(c)?,
+        None => return Ok(None)
+    };
+    match opts.base_patch {
+        Some(ref path) => {
+            let mut patch = Vec::new();
+            File::open(path)
+                .and_then(|mut c| c.read_to_end(&mut patch))
+                .map_err(|e| format!("{}: {}", path, e))?;
+            Ok(Some(bps::apply(&rom, &patch).map_err(|e| format!("{}: {}", path, e))?))
+        },
+        None => Ok(Some(rom))
+    }
+}
+
+fn link_options(opts: &Options) -> Result<linker::LinkOptions,Box<Error>> {
+    Ok(linker::LinkOptions {
+        verbosity: opts.verbosity,
+        mapper: mapper(opts)?,
+        max_size: opts.max_rom_size.map(|c| c as usize * 0x400),
+        base: base_rom(opts)?,
+        freespace_start: opts.freespace_start,
+        // the same patch applied again finds its old blocks by the name of its first input
+        patch_id: object::hash(Path::new(&opts.inputs[0]).file_stem().unwrap_or_default().to_string_lossy().as_bytes()) as u32
+    })
+}
+
+fn write_rom(opts: &Options, rom: &[u8]) -> Result<(),Box<Error>> {
+    if opts.format.is_patch() {
+        // against the clean ROM, so --base-patch ends up in it too
+        let base = read_rom(opts.base.as_ref().ok_or("patches need a base ROM (--base)")?)?;
+        let mut output = BufWriter::new(File::create(opts.output_filename())?);
+        if opts.format == OutputFormat::Ips {
+            ips::write(&base, rom, &mut output)?;
+        } else {
+            output.write_all(&bps::write(&base, rom))?;
+        }
+        return Ok(output.flush()?);
+    }
+    let mut output = BufWriter::new(File::create(opts.output_filename())?);
+    if opts.format == OutputFormat::Smc {
+        // copier header: size in 8KiB units, the rest is unused
+        let mut header = [0u8; 0x200];
+        header[0] = (rom.len() / 0x2000) as u8;
+        header[1] = (rom.len() / 0x2000 >> 8) as u8;
+        output.write_all(&header)?;
+    }
+    output.write_all(rom)?;
+    Ok(())
+}
+
+fn write_symbols(opts: &Options, symbols: &Symbols) -> Result<(),Box<Error>> {
+    let (format, path) = match (opts.symbols, opts.symbols_filename()) {
+        (Some(format), Some(path)) => (format, path),
+        _ => return Ok(())
+    };
+    let mut output = BufWriter::new(File::create(path)?);
+    symbols.write(format, &mut output)?;
+    Ok(output.flush()?)
+}
+
+fn write_listing(opts: &Options, symbols: &Symbols, rom: &[u8]) -> Result<(),Box<Error>> {
+    let path = match opts.listing_filename() {
+        Some(c) => c,
+        None => return Ok(())
+    };
+    let mut output = BufWriter::new(File::create(path)?);
+    listing::write(symbols, rom, &mut output)?;
+    Ok(output.flush()?)
+}
+
+fn write_debug_info(opts: &Options, symbols: &Symbols) -> Result<(),Box<Error>> {
+    let path = match opts.debug_info_filename() {
+        Some(c) => c,
+        None => return Ok(())
+    };
+    let mut output = BufWriter::new(File::create(path)?);
+    debug_info::write(symbols, &mut output)?;
+    Ok(output.flush()?)
+}
+
+fn build(opts: &Options) -> Result<(),Box<Error>> {
+    let compiled = compile(opts, CompilerState::default())?;
+    let mut rom = Vec::new();
+    let symbols = report(linker::link(&mut rom, compiled, &link_options(opts)?))?;
+    write_rom(opts, &rom)?;
+    write_symbols(opts, &symbols)?;
+    write_listing(opts, &symbols, &rom)?;
+    write_debug_info(opts, &symbols)
+}
+
+fn check(opts: &Options) -> Result<(),Box<Error>> {
+    let compiled = compile(opts, CompilerState::default())?;
+    report(linker::link(io::sink(), compiled, &link_options(opts)?))?;
+    Ok(())
+}
+
+fn compile_object(opts: &Options) -> Result<(),Box<Error>> {
+    let state = CompilerState::default();
+    let items = compile(opts, state.clone())?.collect::<Vec<_>>();
+    // stdin can't be hashed, it's just left out
+    let sources = state.borrow().sources.iter()
+        .filter_map(|c| Some((c.to_string_lossy().into_owned(), object::hash_file(c).ok()?)))
+        .collect();
+    let object = match Object::new(sources, items.into_iter()) {
+        Ok(c) => c,
+        Err(errors) => return report(Err(linker::LinkErrors(errors.into_iter().map(linker::LinkError::Compile).collect())))
+    };
+    let mut output = BufWriter::new(File::create(opts.output_filename())?);
+    object.write_to(&mut output)?;
+    Ok(output.flush()?)
+}
+
+fn link(opts: &Options) -> Result<(),Box<Error>> {
+    let mut items = Vec::new();
+    for (i, path) in opts.inputs.iter().enumerate() {
+        let object = File::open(path)
+            .and_then(|c| Object::read_from(io::BufReader::new(c)))
+            .map_err(|e| format!("{}: {}", path, e))?;
+        if opts.verbosity > 0 {
+            for c in object.changed_sources() {
+                Diagnostic::warning(format!("{} changed since {} was compiled", c, path)).emit();
+            }
+        }
+        for mut c in object.items {
+            // every object has code before its first label, those mustn't clash
+            if let CompileData::Chunk { ref mut label, .. } = c {
+                if label == "*root" { *label = format!("*root{}", i); }
+            }
+            items.push(c);
+        }
+    }
+    items.extend(defines(opts)?);
+    let mut rom = Vec::new();
+    let symbols = report(linker::link(&mut rom, items.into_iter(), &link_options(opts)?))?;
+    write_rom(opts, &rom)?;
+    write_symbols(opts, &symbols)?;
+    write_listing(opts, &symbols, &rom)?;
+    write_debug_info(opts, &symbols)
+}
+
+fn disassemble(opts: &Options) -> Result<(),Box<Error>> {
+    let rom = read_rom(&opts.inputs[0])?;
+    let mapper = mapper(opts)?.unwrap_or_default();
+    // unmapped addresses just end the disassembly
+    let offset = |addr: u32| mapper.to_file(addr).unwrap_or(usize::max_value());
+    let start = match opts.start {
+        Some(c) => c,
+        None => {
+            let reset = rom.get(offset(0xFFFC)..offset(0xFFFE)).ok_or("ROM too small to contain a header")?;
+            reset[0] as u32 | (reset[1] as u32) << 8
+        }
+    };
+    print!("{}", disasm::disassemble(&rom, start, opts.count.unwrap_or(64), offset));
     Ok(())
 }
@@ -8,161 +8,834 @@ use std::io::SeekFrom;
 use std::io::Cursor;
 
 use std::time::Instant;
+use std::iter;
+use std::mem;
+use std::cmp::Reverse;
 
 use std::error::Error;
+use std::fmt;
+use std::io;
 
 use std::collections::{HashMap,HashSet};
+use std::path::Path;
 
 use byteorder::WriteBytesExt;
 use byteorder::LittleEndian;
 
 use linked_hash_map::LinkedHashMap;
 
-use compiler::{CompileData,LabeledChunk};
+use compiler::{CompileData,CompileError,LabeledChunk};
 
-use instructions::SizeHint;
+use instructions::{Instruction,SizeHint};
+use addrmodes::AddressingMode;
 
 use expression::{Expression,ExprNode};
 
-use attributes::Attribute;
+use attributes::{Attribute,set_once};
 
-// Anything that isn't directly bank data (lorom mode, etc.), also TODO
-struct BankContext {
-    
+use mapper::Mapper;
+use header::{self,Header};
+use symbols::{Symbols,SourceLine};
+
+use diagnostics::{Diagnostic,Level};
+use colors::prelude::*;
+
+use lexer::Location;
+
+#[derive(Debug)]
+pub enum LinkError {
+    Compile(CompileError),
+    LabelNotFound { label: String, chunk: String, location: Option<(Location, u32)> },
+    CantCollapse { expr: String, chunk: String, location: Option<(Location, u32)> },
+    RecursionTooDeep { chunk: String, location: Option<(Location, u32)> },
+    InvalidSize { size: SizeHint, chunk: String, location: Option<(Location, u32)> },
+    OutOfRange { value: i32, size: SizeHint, chunk: String, location: Option<(Location, u32)> },
+    BranchTooFar { target: String, distance: i32, size: SizeHint, chunk: String, location: Option<(Location, u32)> },
+    // no addressing mode of the instruction reaches the address
+    Unreachable { name: String, addr: u32, dp: u16, db: u8, chunk: String, location: Option<(Location, u32)> },
+    // a 16-bit address to a label that isn't in the bank it's used from
+    WrongBank { label: String, bank: u8, chunk: String, chunk_bank: u8, data_bank: bool, location: Option<(Location, u32)> },
+    DoesntFit { label: String, size: usize },
+    RomFull { label: String, size: usize, max: usize },
+    InvalidPin { label: String, addr: u32, size: usize },
+    TooBig { label: String, size: usize },
+    SpanningCode(String),
+    PinOverlap { label: String, other: String, addr: u32 },
+    // a chain of chunks falling through into each other that can't be placed in one piece
+    ChainTooBig { label: String, size: usize },
+    ChainBanks { label: String, bank: u8, other: String, other_bank: u8 },
+    ChainPins { label: String, other: String },
+    // label attributes like #[start] on defines that aren't constant
+    NonConstantDefine(String),
+    MissingVector(&'static str),
+    VectorBank(&'static str, u32),
+    NotSa1(&'static str),
+    InvalidBank { label: String, bank: u8 },
+    ConflictingAttribute(&'static str),
+    UnmappedFreespace(u32),
+    // two labels with the same name that are visible from the same place
+    DuplicateLabel { label: String, location: Option<(Location, u32)>, first: Option<(Location, u32)> },
+    MissingImport { label: String, location: Option<(Location, u32)> },
+    // #[replace(..)]
+    ReplaceNotFound { label: String, location: Option<(Location, u32)> },
+    ReplacedTwice { label: String, location: Option<(Location, u32)> },
+    ReplacementTooBig { label: String, size: usize, max: usize, location: Option<(Location, u32)> },
+    ReplacementFallsThrough { label: String, location: Option<(Location, u32)> },
+    // only a warning
+    ReplacementDiverges { label: String, next: String, location: Option<(Location, u32)> },
+    IO(io::Error)
 }
 
-#[derive(Default)]
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::LinkError::*;
+        match self {
+            Compile(e) => write!(f, "{}", e),
+            LabelNotFound { label, .. } => write!(f, "label {} not found", label),
+            CantCollapse { expr, .. } => write!(f, "can't resolve expression {}", expr),
+            RecursionTooDeep { .. } => write!(f, "expression nested too deeply (64 max)"),
+            InvalidSize { size, .. } => write!(f, "can't write an expression with size {:?}", size),
+            OutOfRange { value, size, .. } => write!(f, "{}${:X} doesn't fit in a {}",
+                if *value < 0 { "-" } else { "" }, value.abs(), size_name(*size)),
+            BranchTooFar { target, distance, size, .. } => write!(f, "{} is {} bytes away, out of range for {} branch",
+                target, distance, if *size == SizeHint::RelByte { "an 8-bit" } else { "a 16-bit" }),
+            Unreachable { name, addr, dp, db, .. } => write!(f,
+                "{} can't reach ${:06X} with the direct page at ${:04X} and the data bank at ${:02X}", name, addr, dp, db),
+            WrongBank { label, bank, chunk, chunk_bank, data_bank, .. } => write!(f,
+                "{} is in bank ${:02X}, it can't be reached with a 16-bit address from {} {} ${:02X}",
+                label, bank, chunk, if *data_bank { "with the data bank set to" } else { "in bank" }, chunk_bank),
+            DoesntFit { label, size } => write!(f, "can't fit {} (size ${:04X})", label, size),
+            RomFull { label, size, .. } => write!(f, "ran out of space for {} (size ${:04X})", label, size),
+            TooBig { label, size } => write!(f, "{} (size ${:04X}) is bigger than a bank", label, size),
+            SpanningCode(label) => write!(f, "{} contains code, it can't be split across banks", label),
+            InvalidPin { label, addr, size } => write!(f, "can't pin {} (size ${:04X}) to ${:06X}", label, size, addr),
+            PinOverlap { label, other, addr } => write!(f, "{} pinned to ${:06X} overlaps with {}", label, addr,
+                if other == "*header" { "the internal header" } else { other }),
+            ChainTooBig { label, size } => write!(f, "{} and the labels it falls through into (size ${:04X}) don't fit in a bank", label, size),
+            ChainBanks { label, bank, other, other_bank } => write!(f, "{} falls through into {}, but they're in different banks (${:02X} and ${:02X})",
+                label, other, bank, other_bank),
+            ChainPins { label, other } => write!(f, "{} falls through into {}, but they're pinned apart", label, other),
+            NonConstantDefine(label) => write!(f, "linker attributes on the non-constant define {} are not supported (yet!)", label),
+            MissingVector(name) => write!(f, "no {} vector", name),
+            VectorBank(name, addr) => write!(f, "the {} vector has to be reachable from bank $00, found ${:06X}", name, addr),
+            NotSa1(name) => write!(f, "the {} vector is only used with the sa1 mapper", name),
+            InvalidBank { label, bank } => write!(f, "bank ${:02X} (used by {}) isn't mapped to the ROM", bank, label),
+            ConflictingAttribute(name) => write!(f, "conflicting values for #![{}(..)]", name),
+            UnmappedFreespace(addr) => write!(f, "the free space can't start at ${:06X}, it isn't mapped to the ROM", addr),
+            DuplicateLabel { label, .. } => write!(f, "label {} is defined more than once", label),
+            MissingImport { label, .. } => write!(f, "{} is imported, but no file exports it", label),
+            ReplaceNotFound { label, .. } => write!(f, "can't replace {}, there's no such label", label),
+            ReplacedTwice { label, .. } => write!(f, "{} is replaced more than once", label),
+            ReplacementTooBig { label, size, max, .. } => write!(f,
+                "the replacement for {} (size ${:04X}) is bigger than the pinned original (size ${:04X})", label, size, max),
+            ReplacementFallsThrough { label, .. } => write!(f,
+                "the replacement for {} falls through into the next label, the original doesn't", label),
+            ReplacementDiverges { label, next, .. } => write!(f,
+                "{} fell through into {}, its replacement doesn't", label, next),
+            IO(e) => write!(f, "{}", e)
+        }
+    }
+}
+
+impl LinkError {
+    pub fn diagnostic(&self) -> Diagnostic {
+        use self::LinkError::*;
+        match self {
+            Compile(e) => e.diagnostic(),
+            LabelNotFound { chunk, location, .. } | CantCollapse { chunk, location, .. }
+                | RecursionTooDeep { chunk, location } | InvalidSize { chunk, location, .. } => {
+                Diagnostic::error(self.to_string())
+                    .with_span(location.clone())
+                    .with_note(format!("in chunk {}", chunk))
+            },
+            OutOfRange { chunk, location, .. } => Diagnostic::error(self.to_string())
+                .with_span(location.clone())
+                .with_note(format!("in chunk {}", chunk)),
+            BranchTooFar { size, location, .. } => Diagnostic::error(self.to_string())
+                .with_span(location.clone())
+                .with_note(if *size == SizeHint::RelByte {
+                    "8-bit branches reach -128 to 127 bytes, use BRL (or a branch with the opposite condition over a BRL), \
+                    #[relax_branches] does that automatically"
+                } else {
+                    "16-bit branches reach -32768 to 32767 bytes, use JML"
+                }),
+            Unreachable { chunk, location, .. } => Diagnostic::error(self.to_string())
+                .with_span(location.clone())
+                .with_note(format!("in chunk {}, see #[direct_page(..)] and #[data_bank(..)]", chunk)),
+            WrongBank { location, .. } => Diagnostic::error(self.to_string())
+                .with_span(location.clone())
+                .with_note("use a long address, or put both in the same bank with #[bank(..)]"),
+            RomFull { max, .. } => Diagnostic::error(self.to_string())
+                .with_note(format!("the ROM is limited to {} KiB, see #![max_rom_size(..)] and --max-rom-size", max / 0x400)),
+            TooBig { .. } => Diagnostic::error(self.to_string())
+                .with_note("data can be split across banks with #[span_banks]"),
+            SpanningCode(_) => Diagnostic::error(self.to_string())
+                .with_note("the program counter wraps around inside the bank instead of going to the next one"),
+            InvalidPin { .. } => Diagnostic::error(self.to_string())
+                .with_note("the address has to be in the ROM and the chunk can't cross a bank boundary"),
+            ChainTooBig { .. } | ChainBanks { .. } | ChainPins { .. } => Diagnostic::error(self.to_string())
+                .with_note("labels without a jump or return at the end have to be placed right before the next one"),
+            InvalidBank { .. } => Diagnostic::error(self.to_string())
+                .with_note("check the mapper and #![max_rom_size(..)]"),
+            MissingVector(name) => Diagnostic::error(self.to_string())
+                .with_note(format!("mark a label with #[{}]", name.to_lowercase())),
+            DuplicateLabel { location, first, .. } => {
+                let diag = Diagnostic::error(self.to_string()).with_span(location.clone());
+                let diag = match first {
+                    Some((c, _)) => diag.with_note(format!("the first one is at {}", c)),
+                    None => diag
+                };
+                diag.with_note("labels that are only used in their own file can be #[private]")
+            },
+            MissingImport { location, .. } => Diagnostic::error(self.to_string())
+                .with_span(location.clone())
+                .with_note("private labels (#[private], or after #![private]) can't be imported, see #[export]"),
+            ReplaceNotFound { location, .. } | ReplacedTwice { location, .. } => Diagnostic::error(self.to_string())
+                .with_span(location.clone()),
+            ReplacementTooBig { location, .. } => Diagnostic::error(self.to_string())
+                .with_span(location.clone())
+                .with_note("it would overwrite whatever comes after the original"),
+            ReplacementFallsThrough { location, .. } | ReplacementDiverges { location, .. } => Diagnostic::error(self.to_string())
+                .with_span(location.clone())
+                .with_note("replacements are placed where the original was, so they should end the same way"),
+            c => Diagnostic::error(c.to_string())
+        }
+    }
+}
+
+// All the errors from a link, so they can be reported at once
+#[derive(Debug)]
+pub struct LinkErrors(pub Vec<LinkError>);
+
+impl fmt::Display for LinkErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0.len() {
+            1 => write!(f, "aborting due to previous error"),
+            c => write!(f, "aborting due to {} previous errors", c)
+        }
+    }
+}
+
+impl Error for LinkErrors {}
+
 struct Bank {
-    // also used as current position
-    size: usize,
-    chunks: LinkedHashMap<String,(usize,LabeledChunk)>,
-    // todo: pinned chunks
+    capacity: usize,
+    // (offset, address, chunk)
+    chunks: LinkedHashMap<String,(usize,u32,LabeledChunk)>,
+    // (start, end, label) of everything in the bank, sorted
+    used: Vec<(usize, usize, String)>
 }
 
 impl Bank {
-    fn append(&mut self, label: String, chunk: LabeledChunk) -> Option<usize> {
-        let size = chunk.size();
-        //println!("{}: ${:04X}", label, self.size);
-        self.chunks.insert(label, (self.size, chunk));
-        self.size += size;
-        Some(self.size)
-    }
-    fn fits(&self, bank: u8, chunk: &LabeledChunk) -> bool {
-        // lorom only
-        // Currently, header is very hacked together. In the future, the header will be pinned to
-        // 0x7FC0 automatically and exist in the same space as the rest of the chunks
-                                   // Make space for the header
-        self.size + chunk.size() <= if bank == 0 { 0x7FC0 } else { 0x8000 }
+    fn new(capacity: usize) -> Self {
+        Self { capacity, chunks: LinkedHashMap::new(), used: Vec::new() }
+    }
+    fn occupy(&mut self, start: usize, end: usize, label: String) {
+        let pos = self.used.iter().position(|c| c.0 >= end).unwrap_or(self.used.len());
+        self.used.insert(pos, (start, end, label));
+    }
+    fn append(&mut self, label: String, offset: usize, addr: u32, chunk: LabeledChunk) {
+        self.occupy(offset, offset + chunk.size(), label.clone());
+        self.chunks.insert(label, (offset, addr, chunk));
+    }
+    // (start, end) of every free range from `start` on
+    fn gaps(&self, start: usize) -> Vec<(usize, usize)> {
+        let mut gaps = Vec::new();
+        let mut pos = start;
+        for &(from, to, _) in self.used.iter() {
+            if from > pos { gaps.push((pos, from)); }
+            pos = pos.max(to);
+        }
+        if pos < self.capacity { gaps.push((pos, self.capacity)); }
+        gaps
+    }
+    // The smallest gap `len` bytes fit in, as (offset, gap size).
+    // `start` is the lowest offset the chunk may be placed at
+    fn best_fit(&self, start: usize, len: usize) -> Option<(usize, usize)> {
+        self.gaps(start).into_iter()
+            .map(|(from, to)| (from, to - from))
+            .filter(|c| c.1 >= len)
+            .min_by_key(|c| c.1)
+    }
+    fn free(&self) -> usize {
+        self.gaps(0).iter().map(|c| c.1 - c.0).sum()
+    }
+    // Takes up the start of the bank for a chunk that begins in an earlier one
+    fn reserve(&mut self, label: String, len: usize) {
+        self.occupy(0, len, label);
+    }
+    // The label of whatever is already in the range, if anything
+    fn overlap(&self, start: usize, end: usize) -> Option<&str> {
+        self.used.iter().find(|c| c.0 < end && start < c.1).map(|c| &*c.2)
     }
     fn is_clear(&self) -> bool {
-        self.size == 0
+        self.used.is_empty()
+    }
+    // Marks whatever is still free in the range as used
+    fn fill(&mut self, start: usize, end: usize, label: &str) {
+        for (from, to) in self.gaps(0) {
+            let (from, to) = (from.max(start), to.min(end));
+            if from < to { self.occupy(from, to, label.to_string()); }
+        }
+    }
+}
+
+// Chunks that fall through into each other, in source order. They're placed as one block so
+// execution doesn't run off the end of one into whatever comes next.
+struct Chain {
+    chunks: Vec<(String, LabeledChunk)>
+}
+
+impl Chain {
+    fn size(&self) -> usize {
+        self.chunks.iter().map(|c| c.1.size()).sum()
     }
-    // In the future, it will be possible to pin chunks to addresses after $00, and data from
-    // this bank may be adjusted to fit automatically.
-    // For now, it's literally just whether the bank is clean
-    fn fits_head(&self, chunk: &LabeledChunk) -> bool {
-        self.size == 0
+    fn label(&self) -> &str {
+        // the RATS tag in front of it isn't what anyone is looking for
+        self.chunks.iter().map(|c| &*c.0).find(|c| !c.starts_with(RATS)).unwrap_or(&self.chunks[0].0)
+    }
+    fn bank_hint(&self) -> Option<u8> {
+        self.chunks.iter().filter_map(|c| c.1.bank_hint).next()
+    }
+    // Every chunk has to agree on the bank
+    fn check_hints(&self) -> Result<(), LinkError> {
+        let mut hints = self.chunks.iter().filter_map(|c| c.1.bank_hint.map(|d| (&c.0, d)));
+        let (label, bank) = match hints.next() {
+            Some(c) => c,
+            None => return Ok(())
+        };
+        match hints.find(|c| c.1 != bank) {
+            Some((other, other_bank)) => Err(LinkError::ChainBanks { label: label.clone(), bank, other: other.clone(), other_bank }),
+            None => Ok(())
+        }
+    }
+    fn is_vector(&self) -> bool {
+        self.chunks.iter().any(|c| c.1.attrs.iter().any(|c| c.vector_name().is_some()))
+    }
+    fn pinned(&self) -> bool {
+        self.chunks.iter().any(|c| c.1.pinned.is_some())
+    }
+    // Splits after every diverging chunk
+    fn split(chunks: Vec<(String, LabeledChunk)>) -> Vec<Self> {
+        let mut chains = Vec::new();
+        let mut current = Vec::new();
+        for c in chunks {
+            let diverging = c.1.diverging;
+            current.push(c);
+            if diverging {
+                chains.push(Chain { chunks: mem::replace(&mut current, Vec::new()) });
+            }
+        }
+        if !current.is_empty() { chains.push(Chain { chunks: current }); }
+        chains
+    }
+    // Code reaches other chunks in its bank through 16-bit addresses and branches, so chains that
+    // refer to each other like that are merged as long as the result still fits in a bank
+    fn group(chains: Vec<Self>, bank_size: usize) -> Vec<Self> {
+        let mut parent = (0..chains.len()).collect::<Vec<_>>();
+        let mut sizes = chains.iter().map(Chain::size).collect::<Vec<_>>();
+        let mut hints = chains.iter().map(Chain::bank_hint).collect::<Vec<_>>();
+        let mut vectors = chains.iter().map(Chain::is_vector).collect::<Vec<_>>();
+        fn find(parent: &mut Vec<usize>, mut i: usize) -> usize {
+            while parent[i] != i { parent[i] = parent[parent[i]]; i = parent[i]; }
+            i
+        }
+        {
+            let owner = chains.iter().enumerate()
+                .flat_map(|(i, c)| c.chunks.iter().map(move |c| (&*c.0, i)))
+                .collect::<HashMap<_,_>>();
+            for (i, chain) in chains.iter().enumerate() {
+                let refs = chain.chunks.iter().flat_map(|c| c.1.pending_exprs.iter().map(move |r| (r, c.1.data_bank)));
+                for (r, data_bank) in refs {
+                    // 16-bit pointers and immediates usually end up in the same bank too, only data
+                    // in a declared data bank isn't tied to the code
+                    let near = match r.expr.size {
+                        SizeHint::Word | SizeHint::RelByte | SizeHint::RelWord => !r.same_bank || r.program_bank || data_bank.is_none(),
+                        _ => false
+                    };
+                    if !near { continue; }
+                    let mut others = Vec::new();
+                    r.expr.each(|c| if let ExprNode::Label(d) = c {
+                        if let Some(&j) = owner.get(&**d) { others.push(j); }
+                    });
+                    for j in others {
+                        let (a, b) = (find(&mut parent, i), find(&mut parent, j));
+                        // the vectors have their own bank
+                        let compatible = match (hints[a], hints[b]) {
+                            (Some(c), Some(d)) => c == d,
+                            (Some(_), None) => !vectors[b],
+                            (None, Some(_)) => !vectors[a],
+                            (None, None) => true
+                        };
+                        if a == b || !compatible || sizes[a] + sizes[b] > bank_size { continue; }
+                        parent[b] = a;
+                        sizes[a] += sizes[b];
+                        hints[a] = hints[a].or(hints[b]);
+                        vectors[a] |= vectors[b];
+                    }
+                }
+            }
+        }
+        // every group ends up where its first chain was
+        let mut groups = (0..chains.len()).map(|_| Vec::new()).collect::<Vec<_>>();
+        for (i, chain) in chains.into_iter().enumerate() {
+            let root = find(&mut parent, i);
+            groups[root].extend(chain.chunks);
+        }
+        groups.into_iter().filter(|c| !c.is_empty()).map(|chunks| Chain { chunks }).collect()
     }
 }
 
 struct Banks {
+    mapper: Mapper,
+    header: Header,
     content: Vec<Bank>,
-    // todo: an _actual_ label graph
-    last_bank: u8,
-    refs: HashMap<String, (u8, usize)>,
-    defines: HashMap<String, Expression>
+    // full SNES addresses
+    refs: HashMap<String, u32>,
+    // vectors set by defines, the only refs that don't come from placing chunks
+    define_vectors: HashMap<String, u32>,
+    defines: HashMap<String, Expression>,
+    // the ROM being patched, if any
+    base: Option<Vec<u8>>,
+    // file offsets of the base ROM's free space, and of the blocks from a previous build in it
+    base_free: Vec<(usize, usize)>,
+    reclaimed: Vec<(usize, usize)>,
+    patch_id: u32,
+    errors: Vec<LinkError>,
+    warnings: Vec<LinkError>
 }
 
 impl Banks {
-    fn new() -> Self {
+    // Every bank up to the maximum size is available, only the used ones get written
+    fn new(mapper: Mapper, header: Header, max_size: usize, base: Option<Vec<u8>>) -> Self {
+        let bank_size = mapper.bank_size();
+        let header_bank = mapper.vector_bank();
+        let count = (max_size / bank_size).max(header_bank + 1).min(mapper.max_banks());
         Self {
-            // TODO: scale the rom accordingly
-            content: (0..32).map(|_| Bank::default()).collect(),
-            last_bank: 0,
+            mapper,
+            header,
+            content: (0..count).map(|_| Bank::new(bank_size)).collect(),
             defines: Default::default(),
-            refs: Default::default()
+            refs: Default::default(),
+            define_vectors: Default::default(),
+            base,
+            base_free: Vec::new(),
+            reclaimed: Vec::new(),
+            patch_id: 0,
+            errors: Vec::new(),
+            warnings: Vec::new()
         }
     }
     fn add_define(&mut self, label: String, attrs: Vec<Attribute>, expr: Expression) {
-        use attributes::Attribute::*;
         {
-            let addr = || (0, if let ExprNode::Constant(c) = expr.root { c } else {
-                panic!("Linker attributes for expressions are not supported (yet!)");
-            } as usize);
+            let addr = if let ExprNode::Constant(c) = expr.root { Some(c as u32) } else { None };
             for i in attrs {
-                match i {
-                    Start => { self.refs.insert("*Start".to_string(), addr()); },
-                    NMI =>   { self.refs.insert("*NMI".to_string(), addr()); },
-                    IRQ =>   { self.refs.insert("*IRQ".to_string(), addr()); },
-                    BRK =>   { self.refs.insert("*BRK".to_string(), addr()); },
-                    _ => {}
+                let name = match i.vector_name() {
+                    Some(c) => c,
+                    None => continue
+                };
+                match addr {
+                    Some(addr) => { self.define_vectors.insert(format!("*{}", name), addr); },
+                    None => self.errors.push(LinkError::NonConstantDefine(label.clone()))
                 }
             }
         }
         self.defines.insert(label, expr);
     }
-    fn append_spanning_chunk(&mut self, label: String, chunk: LabeledChunk) -> Option<usize> {
-        // LOROM only
-        let req_empty_banks = chunk.size()/0x8000;  // How many full banks does this data take?
-        if req_empty_banks > 0 {
-            panic!("I don't support chunks larger than 32k yet");
-            //self.content.windows(req_empty_banks)
-            // ...
-        }
-        panic!();
-
-    }
-    fn append_chunk(&mut self, label: String, chunk: LabeledChunk) -> Option<usize> {
-        let (bank_id, bank) = match chunk.bank_hint {
+    // Data that doesn't fit in a bank goes into consecutive empty banks. It's still stored as one
+    // chunk in the first one, since the banks are next to each other in the file.
+    fn append_spanning_chunk(&mut self, label: String, chunk: LabeledChunk) -> Result<u32, LinkError> {
+        if chunk.has_code { return Err(LinkError::SpanningCode(label)); }
+        let mapper = self.mapper;
+        let bank_size = mapper.bank_size();
+        let count = (chunk.size() + bank_size - 1) / bank_size;
+        let max = self.content.len() * bank_size;
+        let first = match chunk.bank_hint {
             Some(c) => {
-                let bank = &mut self.content[c as usize];
-                if !bank.fits(c, &chunk) { return None; }
-                (c as usize, bank)
+                let first = mapper.bank_index(c)
+                    .filter(|c| *c < self.content.len())
+                    .ok_or_else(|| LinkError::InvalidBank { label: label.clone(), bank: c })?;
+                let free = self.content.get(first..first + count).map(|c| c.iter().all(Bank::is_clear));
+                if free != Some(true) { return Err(LinkError::DoesntFit { label, size: chunk.size() }); }
+                first
             },
-            None => {   // for shit like JSL routines
-                // find an appropriate bank
-                self.content.iter_mut().enumerate().skip(self.last_bank as usize).find(|x| x.1.fits(x.0 as u8, &chunk))?
+            None => match self.content.windows(count).position(|c| c.iter().all(Bank::is_clear)) {
+                Some(c) => c,
+                None => return Err(LinkError::RomFull { label, size: chunk.size(), max })
             }
         };
-
-        self.last_bank = bank_id as u8;
-        let addr = (bank_id as u8, bank.size + 0x8000);
+        let mut left = chunk.size();
+        for bank in self.content[first + 1..first + count].iter_mut() {
+            left -= bank_size;
+            bank.reserve(label.clone(), left.min(bank_size));
+        }
+        let addr = mapper.address(first * bank_size, chunk.bank_hint);
+        self.add_refs(&label, &chunk, addr);
+        self.content[first].append(label, 0, addr, chunk);
+        Ok(addr)
+    }
+    fn add_refs(&mut self, label: &str, chunk: &LabeledChunk, addr: u32) {
         for i in chunk.attrs.iter() {
-            use attributes::Attribute::*;
-            match i {
-                Start => { self.refs.insert("*Start".to_string(), addr); },
-                NMI =>   { self.refs.insert("*NMI".to_string(), addr); },
-                IRQ =>   { self.refs.insert("*IRQ".to_string(), addr); },
-                BRK =>   { self.refs.insert("*BRK".to_string(), addr); },
-                _ => {}
+            if let Some(name) = i.vector_name() {
+                self.refs.insert(format!("*{}", name), addr);
+            }
+        }
+        self.refs.insert(label.to_string(), addr);
+    }
+    // Pinned chunks are placed before anything else, along with whatever they fall through into
+    // (or what falls through into them)
+    fn append_pinned_chain(&mut self, chain: Chain) -> Result<Vec<(String, u32, usize)>, LinkError> {
+        let bank_size = self.mapper.bank_size();
+        let size = chain.size();
+        let mut start = None;
+        let mut offset = 0;
+        for (label, chunk) in chain.chunks.iter() {
+            if let Some(addr) = chunk.pinned {
+                if (offset as u32) > addr {
+                    return Err(LinkError::InvalidPin { label: chain.label().to_string(), addr, size });
+                }
+                match start {
+                    None => start = Some((addr - offset as u32, label, addr)),
+                    Some((c, other, _)) if c != addr - offset as u32 => return Err(LinkError::ChainPins { label: other.clone(), other: label.clone() }),
+                    _ => {}
+                }
+            }
+            offset += chunk.size();
+        }
+        let (addr, label, pin) = match start {
+            Some((addr, label, pin)) => (addr, label.clone(), pin),
+            None => unreachable!()
+        };
+        let file_offset = match self.mapper.to_file(addr) {
+            Some(c) if c / bank_size < self.content.len() && c % bank_size + size <= bank_size => c,
+            _ => return Err(LinkError::InvalidPin { label, addr: pin, size })
+        };
+        let (bank_id, mut offset) = (file_offset / bank_size, file_offset % bank_size);
+        if let Some(other) = self.content[bank_id].overlap(offset, offset + size) {
+            return Err(LinkError::PinOverlap { label, other: other.to_string(), addr });
+        }
+        let mut placed = Vec::new();
+        for (label, chunk) in chain.chunks {
+            let len = chunk.size();
+            let addr = addr + (offset - file_offset % bank_size) as u32;
+            self.add_refs(&label, &chunk, addr);
+            self.content[bank_id].append(label.clone(), offset, addr, chunk);
+            placed.push((label, addr, len));
+            offset += len;
+        }
+        Ok(placed)
+    }
+    // Places a chain in the bank with the smallest gap it fits in, returns the address of every chunk
+    fn append_chain(&mut self, chain: Chain) -> Result<Vec<(String, u32, usize)>, LinkError> {
+        let mapper = self.mapper;
+        let bank_size = mapper.bank_size();
+        let size = chain.size();
+        chain.check_hints()?;
+        if size > bank_size {
+            // Labels right before big data are fine, they're all at the same address
+            if chain.chunks[..chain.chunks.len() - 1].iter().any(|c| c.1.size() > 0) {
+                return Err(LinkError::ChainTooBig { label: chain.label().to_string(), size });
+            }
+            let mut chunks = chain.chunks;
+            let (label, chunk) = chunks.pop().unwrap();
+            let span = chunk.attrs.iter().any(|c| if let Attribute::SpanBanks(true) = c { true } else { false });
+            if !span { return Err(LinkError::TooBig { label, size }); }
+            let addr = self.append_spanning_chunk(label.clone(), chunk)?;
+            let mut placed = Vec::new();
+            for (label, chunk) in chunks {
+                let offset = self.mapper.to_file(addr).unwrap();
+                self.add_refs(&label, &chunk, addr);
+                self.content[offset / bank_size].append(label.clone(), offset % bank_size, addr, chunk);
+                placed.push((label, addr, 0));
+            }
+            placed.push((label, addr, size));
+            return Ok(placed);
+        }
+        let max = self.content.len() * bank_size;
+        let label = chain.label().to_string();
+        let is_vector = chain.is_vector();
+        let bank_hint = chain.bank_hint();
+        let hint = match bank_hint {
+            Some(c) => Some(mapper.bank_index(c)
+                .filter(|c| *c < self.content.len())
+                .ok_or_else(|| LinkError::InvalidBank { label: label.clone(), bank: c })?),
+            // the vectors can only point to bank $00
+            None if is_vector => Some(mapper.vector_bank()),
+            None => None
+        };
+        // In HiROM, the lower half of the bank isn't visible from bank $00
+        let start = |bank_id: usize| if is_vector && bank_id == mapper.vector_bank() {
+            mapper.vector_start() % bank_size
+        } else { 0 };
+        let (bank_id, mut offset) = match hint {
+            Some(c) => match self.content[c].best_fit(start(c), size) {
+                Some((offset, _)) => (c, offset),
+                None => return Err(LinkError::DoesntFit { label, size })
+            },
+            // ties go to the lowest bank, so the ROM stays as small as possible
+            None => match self.content.iter().enumerate()
+                    .filter_map(|(i, c)| c.best_fit(start(i), size).map(|(offset, gap)| (gap, i, offset)))
+                    .min() {
+                Some((_, i, offset)) => (i, offset),
+                None => return Err(LinkError::RomFull { label, size, max })
+            }
+        };
+        let mut placed = Vec::new();
+        for (label, chunk) in chain.chunks {
+            let len = chunk.size();
+            // the whole chain is seen through the same bank
+            let addr = mapper.address(bank_id * bank_size + offset, chunk.bank_hint.or(bank_hint));
+            self.add_refs(&label, &chunk, addr);
+            self.content[bank_id].append(label.clone(), offset, addr, chunk);
+            placed.push((label, addr, len));
+            offset += len;
+        }
+        Ok(placed)
+    }
+    // Pinned chains go first so everything else can flow around them. After that it's best fit
+    // decreasing: hinted chains claim their banks first, then the biggest ones go wherever the
+    // least space is left over.
+    // Returns (label, address, size, time taken) of every chunk
+    fn layout(&mut self, chunks: Vec<(String, LabeledChunk)>) -> Vec<(String, u32, usize, u64)> {
+        let bank_size = self.mapper.bank_size();
+        // nothing is left from an earlier layout
+        self.refs = self.define_vectors.clone();
+        let (pinned, chains): (Vec<_>, Vec<_>) = Chain::split(chunks).into_iter().partition(Chain::pinned);
+        let mut chains = Chain::group(chains, bank_size);
+        chains.sort_by_key(|c| {
+            let size = c.size();
+            (size <= bank_size, c.bank_hint().is_none() && !c.is_vector(), Reverse(size))
+        });
+        let mut result = Vec::new();
+        let mut now = Instant::now();
+        for chain in pinned {
+            let placed = self.append_pinned_chain(chain);
+            self.placed(placed, &mut result, &mut now);
+        }
+        // pinned chunks may overwrite the base ROM, nothing else can
+        self.reserve_base();
+        for mut chain in chains {
+            // tagged so other tools leave it alone, and the next build can find it again
+            if self.base.is_some() && chain.size() + 16 <= bank_size {
+                let tag = rats_chunk(chain.size(), self.patch_id);
+                let label = format!("{}{}", RATS, chain.label());
+                chain.chunks.insert(0, (label, tag));
+            }
+            let placed = self.append_chain(chain);
+            self.placed(placed, &mut result, &mut now);
+        }
+        result
+    }
+    fn placed(&mut self, placed: Result<Vec<(String, u32, usize)>, LinkError>, result: &mut Vec<(String, u32, usize, u64)>, now: &mut Instant) {
+        match placed {
+            Ok(placed) => for (label, addr, len) in placed {
+                result.push((label, addr, len, micros(*now)));
+            },
+            Err(e) => self.errors.push(e)
+        }
+        *now = Instant::now();
+    }
+    // Looks for free space in the base ROM, from `start` on
+    fn scan_base(&mut self, start: usize) {
+        if let Some(ref base) = self.base {
+            let (free, reclaimed) = scan_base(base, start, self.patch_id);
+            self.base_free = free;
+            self.reclaimed = reclaimed;
+        }
+    }
+    // Everything the base ROM covers counts as used, except for its free space
+    fn reserve_base(&mut self) {
+        let len = match self.base { Some(ref c) => c.len(), None => return };
+        let bank_size = self.mapper.bank_size();
+        let mut used = Vec::new();
+        let mut pos = 0;
+        for &(start, end) in self.base_free.iter() {
+            if start > pos { used.push((pos, start)); }
+            pos = end;
+        }
+        if pos < len { used.push((pos, len)); }
+        for (start, end) in used {
+            for (i, bank) in self.content.iter_mut().enumerate().skip(start / bank_size) {
+                let bank_start = i * bank_size;
+                if bank_start >= end { break; }
+                bank.fill(start.saturating_sub(bank_start), end - bank_start, "*base");
+            }
+        }
+    }
+    // Chunks have to be placed again after they've grown
+    fn take_chunks(&mut self) -> Vec<(String, LabeledChunk)> {
+        let mut chunks = Vec::new();
+        for bank in self.content.iter_mut() {
+            let capacity = bank.capacity;
+            let bank = mem::replace(bank, Bank::new(capacity));
+            chunks.extend(bank.chunks.into_iter()
+                .filter(|c| !c.0.starts_with(RATS))
+                .map(|(label, (_, _, chunk))| (label, chunk)));
+        }
+        chunks
+    }
+    // The value of an expression now that everything is placed, if it's known yet
+    fn evaluate(&self, expr: &Expression, base: i32) -> Option<i32> {
+        let mut expr = expr.clone();
+        for _ in 0..64 {
+            let defines = &self.defines;
+            expr.each_mut(|c| if let Some(e) = match c { ExprNode::Label(d) => defines.get(d), _ => None } {
+                *c = e.root.clone();
+            });
+            if !expr.reduce() { break; }
+        }
+        let mut known = true;
+        let refs = &self.refs;
+        expr.each_mut(|c| match c {
+            ExprNode::Label(d) => match refs.get(d) {
+                Some(&addr) => *c = ExprNode::Constant(addr as i32),
+                None => known = false
+            },
+            ExprNode::LabelOffset(d) => *c = ExprNode::Constant(base + *d as i32),
+            _ => {}
+        });
+        expr.reduce();
+        match expr.root {
+            ExprNode::Constant(c) if known => Some(c),
+            _ => None
+        }
+    }
+    // Everything that has to get bigger now that it's placed: branches in chunks with
+    // #[relax_branches] that don't reach, and operands that need a bigger addressing mode
+    fn growth(&self) -> Vec<(String, usize, Grow)> {
+        let mut grow = Vec::new();
+        for bank in self.content.iter() {
+            for (label, (_, addr, chunk)) in bank.chunks.iter() {
+                let relax = chunk.attrs.iter().any(|c| if let Attribute::RelaxBranches = c { true } else { false });
+                for r in chunk.pending_exprs.iter() {
+                    let val = match self.evaluate(&r.expr, *addr as i32) {
+                        Some(c) => c,
+                        None => continue
+                    };
+                    if let Some((ref name, mode)) = r.sizing {
+                        match operand_size(self.mapper, name, mode, r.expr.size, val as u32, registers(chunk, *addr)) {
+                            Some(size) if size != r.expr.size => grow.push((label.clone(), r.offset, Grow::Operand(size))),
+                            _ => {}
+                        }
+                    } else if relax && r.expr.size == SizeHint::RelByte {
+                        let distance = val - (*addr as i32 + r.offset as i32) - 1;
+                        if distance < -0x80 || distance > 0x7F { grow.push((label.clone(), r.offset, Grow::Branch)); }
+                    }
+                }
+            }
+        }
+        grow
+    }
+    // Banks that end up in the ROM. The header always has to be there.
+    fn used_banks(&self) -> usize {
+        let used = self.content.iter().rposition(|c| !c.is_clear()).unwrap_or(0).max(self.mapper.vector_bank()) + 1;
+        let bank_size = self.mapper.bank_size();
+        header::cartridge_size(used * bank_size).min(self.content.len() * bank_size) / bank_size
+    }
+    fn print_free_space(&self) {
+        println!("Free space:");
+        let banks = self.content.iter().enumerate().take(self.used_banks()).collect::<Vec<_>>();
+        for line in banks.chunks(8) {
+            let line = line.iter()
+                .map(|(i, c)| format!("${:02X}: ${:04X}", self.mapper.bank_address(*i) >> 16, c.free()))
+                .collect::<Vec<_>>();
+            println!("    {}", line.join("  "));
+        }
+    }
+    // The header refers to the vectors like labels, so they have to exist
+    fn check_vectors(&mut self) {
+        let mapper = self.mapper;
+        // Missing IRQ and BRK vectors are fine, they just point to $7FFF. A base ROM already has
+        // all of them.
+        let required = if self.base.is_some() { &[][..] } else { &[("Start", None), ("NMI", None), ("IRQ", Some(0x7FFF)), ("BRK", Some(0x7FFF))][..] };
+        for &(name, default) in required.iter() {
+            let key = format!("*{}", name);
+            if self.refs.contains_key(&key) { continue; }
+            if default.is_none() { self.errors.push(LinkError::MissingVector(name)); }
+            self.refs.insert(key, default.unwrap_or(0xFFFF));
+        }
+        // The SA-1 vectors are written to its registers by the program, they only need
+        // to be checked
+        for &name in ["Start", "NMI", "IRQ", "BRK", "SA1Start", "SA1NMI", "SA1IRQ"].iter() {
+            let addr = match self.refs.get(&format!("*{}", name)) {
+                Some(c) => *c,
+                None => continue
             };
+            if name.starts_with("SA1") && mapper != Mapper::Sa1 {
+                self.errors.push(LinkError::NotSa1(name));
+            }
+            // anything in bank $00 is fine, even if it's RAM
+            if addr > 0xFFFF && !mapper.reachable_from_vectors(addr) {
+                self.errors.push(LinkError::VectorBank(name, addr));
+            }
         }
-        self.refs.insert(label.clone(), addr);
-        let addr = bank_id * 0x10000 + bank.size + 0x8000;
-        bank.append(label, chunk);
-        Some(addr)
     }
-    fn seal(&mut self, bank: u8) {
-        // lorom only, again
-        self.content[bank as usize].size = 0x8000;
+    // Every label outside of the header and vectors, with its local labels, every define that
+    // comes out as a constant, and where the statements of each chunk went. `order` is the
+    // position of each chunk in the source.
+    fn symbols(&self, order: &HashMap<String, usize>) -> Symbols {
+        let bank_size = self.mapper.bank_size();
+        let mut labels = Vec::new();
+        let mut lines = Vec::new();
+        for (bank_id, bank) in self.content.iter().enumerate() {
+            for (label, (offset, addr, chunk)) in bank.chunks.iter() {
+                let file_offset = bank_id * bank_size + offset;
+                let position = order.get(label).cloned().unwrap_or(usize::max_value());
+                if let Some((ref location, _)) = chunk.location {
+                    lines.push((position, SourceLine { location: location.clone(), address: *addr, offset: file_offset, len: 0 }));
+                }
+                let ends = chunk.lines.iter().skip(1).map(|c| c.0).chain(iter::once(chunk.size()));
+                for (&(start, ref location), end) in chunk.lines.iter().zip(ends) {
+                    let line = SourceLine { location: location.clone(), address: addr + start as u32, offset: file_offset + start, len: end - start };
+                    lines.push((position, line));
+                }
+                if label.starts_with('*') { continue; }
+                let label = symbol_name(label);
+                labels.push((label.clone(), *addr));
+                labels.extend(chunk.locals.iter().map(|(name, offset)| (format!("{}.{}", label, name), addr + *offset as u32)));
+            }
+        }
+        labels.sort_by_key(|c| c.1);
+        // stable, so the lines of a chunk stay in order
+        lines.sort_by_key(|c| c.0);
+        let mut constants = self.defines.iter()
+            .filter(|c| !c.0.starts_with('*'))
+            .filter_map(|(label, expr)| Some((label.clone(), self.evaluate(expr, 0)?)))
+            .collect::<Vec<_>>();
+        constants.sort();
+        Symbols { mapper: self.mapper, labels, constants, lines: lines.into_iter().map(|c| c.1).collect() }
     }
-    fn write_to<W: Write>(&mut self, mut w: W) -> Result<(), Box<Error>> {
+    fn write_to<W: Write>(&mut self, mut w: W) -> io::Result<()> {
+        let bank_size = self.mapper.bank_size();
+        let rom_size = self.used_banks() * bank_size;
+        self.check_vectors();
+        let base_size = self.base.as_ref().map(Vec::len);
+        let mut rom = match self.base.take() {
+            Some(mut c) => { let len = rom_size.max(c.len()); c.resize(len, 0x00); c },
+            None => vec![0x00; rom_size]
+        };
+        // what's left of the last build is cleared, tags and all
+        for &(start, end) in self.reclaimed.iter() {
+            for c in rom[start..end].iter_mut() { *c = 0x00; }
+        }
         let refs = &self.refs;
         let defines = &self.defines;
-        let mut groups = HashMap::<String,Vec<String>>::new();
-        let mut fallthrough_last = None;
-        for (ref bank_id, ref mut bank) in self.content.iter_mut().enumerate() {
-            let mut bank_contents = Vec::with_capacity(0x8000);
-            for (label, (offset, chunk)) in bank.chunks.iter_mut() {
+        let errors = &mut self.errors;
+        let mapper = self.mapper;
+        for (ref bank_id, ref mut bank) in self.content.iter_mut().enumerate().take(rom_size / bank_size) {
+            for (label, (offset, addr, chunk)) in bank.chunks.iter_mut() {
+                let file_offset = bank_id * bank_size + *offset;
+                let base = *addr as i32;
                 let mut c = Cursor::new(chunk.data.clone());    // Cow?
-                let mut word_refs = Vec::new();
-                if let Some(c) = fallthrough_last.take() {
-                    groups.get_mut(&c).unwrap().push(label.to_string());
-                }
-                if !chunk.diverging { fallthrough_last = Some(label.clone()) }
+                let data_bank = chunk.data_bank;
+                let (dp, db) = registers(chunk, *addr);
                 for mut r in chunk.pending_exprs.iter_mut() {
-                    let (expr_offset,expr) = (r.offset,&mut r.expr);
-                    let abs_offset = *offset + expr_offset;
+                    let (expr_offset,expr,location) = (r.offset,&mut r.expr,&r.location);
+                    let (same_bank, program_bank) = (r.same_bank, r.program_bank);
+                    let sizing = r.sizing.clone();
+                    let mut target = None;
+                    let mut address = false;
+                    let pc = base + expr_offset as i32;
                     let mut size = expr.size;
                     for i in 0.. {
                         expr.each_mut(|c| {
@@ -176,106 +849,319 @@ impl Banks {
                         });
                         // if can't reduce any more, good
                         if !expr.reduce() { break; }
-                        if i > 64 { panic!("Recursion too deep in {} (64 max)", label) }
+                        if i > 64 {
+                            errors.push(LinkError::RecursionTooDeep { chunk: label.clone(), location: location.clone() });
+                            break;
+                        }
                     }
                     expr.each_mut(|c| {
                         use expression::ExprNode::*;
                         match c {
                             Label(d) => {
-                                *c = ExprNode::Constant({
-                                    if size == SizeHint::Word || size == SizeHint::RelByte || size == SizeHint::RelWord {
-                                        word_refs.push(d.to_string());
+                                *c = ExprNode::Constant(match refs.get(d) {
+                                    Some(c) => {
+                                        if target.is_none() { target = Some(d.clone()); }
+                                        address = true;
+                                        *c as i32
+                                    },
+                                    None => {
+                                        errors.push(LinkError::LabelNotFound { label: d.clone(), chunk: label.clone(), location: location.clone() });
+                                        0
                                     }
-                                    let (b,c) = refs.get(d).unwrap_or_else(|| panic!("not found label: {}", d));
-                                    (*b as i32)*0x10000 + *c as i32 + 0x800000
                                 })
                             },
                             LabelOffset(d) => {
-                                *c = ExprNode::Constant(0x8000 + (*offset as i32)+(*d as i32))
+                                address = true;
+                                *c = ExprNode::Constant(base + (*d as i32))
                             },
                             _ => {}
                         }
                     });
                     expr.reduce();
-                    let mut val = if let ExprNode::Constant(c) = expr.root { c } else { panic!("Can't collapse expression {} in {}", expr.root, label) };
+                    let val = if let ExprNode::Constant(c) = expr.root { c } else {
+                        errors.push(LinkError::CantCollapse { expr: expr.root.to_string(), chunk: label.clone(), location: location.clone() });
+                        continue;
+                    };
+                    // The bank of a 16-bit address comes from the CPU, so the label has to be
+                    // visible from there (mirrors count)
+                    if let (true, Some(target)) = (same_bank, &target) {
+                        let from = if program_bank { None } else { data_bank };
+                        let bank = from.map(|c| c as u32).unwrap_or(pc as u32 >> 16 & 0xFF);
+                        if mapper.to_file(bank << 16 | val as u32 & 0xFFFF) != mapper.to_file(val as u32) {
+                            errors.push(LinkError::WrongBank {
+                                label: target.clone(), bank: (val as u32 >> 16) as u8,
+                                chunk: label.clone(), chunk_bank: bank as u8, data_bank: from.is_some(),
+                                location: location.clone()
+                            });
+                        }
+                    }
                     c.seek(SeekFrom::Start(expr_offset as u64)).unwrap();
-                    match size {
-                        SizeHint::Byte => {
-                            //if val > 0xFF { println!("WARNING: expression out of range for byte size"); }
-                            c.write_u8(val as u8).unwrap()
-                        },
-                        SizeHint::Word => {
-                            //if val > 0xFFFF { println!("WARNING: expression out of range for word size"); }
-                            c.write_u16::<LittleEndian>(val as u16).unwrap()
+                    // Operands the linker picked the size of are relative to the direct page, or
+                    // lose their bank
+                    let val = match sizing {
+                        Some((name, mode)) => {
+                            if operand_size(mapper, &name, mode, size, val as u32, (dp, db)) != Some(size) {
+                                errors.push(LinkError::Unreachable {
+                                    name, addr: val as u32, dp: dp as u16, db: db as u8,
+                                    chunk: label.clone(), location: location.clone()
+                                });
+                                continue;
+                            }
+                            match size {
+                                SizeHint::Byte => (val & 0xFFFF) - dp as i32,
+                                SizeHint::Word => val & 0xFFFF,
+                                _ => val
+                            }
                         },
+                        None => val
+                    };
+                    // Negative numbers are fine as long as they sign-extend, addresses of labels
+                    // lose their bank in 16-bit operands
+                    let in_range = match size {
+                        SizeHint::Byte => val >= -0x80 && val <= 0xFF,
+                        SizeHint::Word if address => val >= -0x8000 && val <= 0xFFFFFF,
+                        SizeHint::Word => val >= -0x8000 && val <= 0xFFFF,
+                        SizeHint::Long => val >= -0x800000 && val <= 0xFFFFFF,
+                        _ => true
+                    };
+                    if !in_range {
+                        errors.push(LinkError::OutOfRange { value: val, size, chunk: label.clone(), location: location.clone() });
+                    }
+                    let distance = match size {
+                        SizeHint::RelByte => val - pc - 1,
+                        SizeHint::RelWord => val - pc - 2,
+                        _ => 0
+                    };
+                    let reachable = match size {
+                        SizeHint::RelByte => distance >= -0x80 && distance <= 0x7F,
+                        SizeHint::RelWord => distance >= -0x8000 && distance <= 0x7FFF,
+                        _ => true
+                    };
+                    if !reachable {
+                        errors.push(LinkError::BranchTooFar {
+                            target: target.clone().unwrap_or_else(|| format!("${:06X}", val)),
+                            distance, size, chunk: label.clone(), location: location.clone()
+                        });
+                    }
+                    match size {
+                        SizeHint::Byte => c.write_u8(val as u8).unwrap(),
+                        SizeHint::Word => c.write_u16::<LittleEndian>(val as u16).unwrap(),
                         SizeHint::Long => c.write_u24::<LittleEndian>(val as u32).unwrap(),
-                        SizeHint::RelByte => c.write_i8((val - abs_offset as i32 - 1) as i8).unwrap(),
-                        SizeHint::RelWord => c.write_i16::<LittleEndian>((val - abs_offset as i32 - 2 - 0x8000) as i16).unwrap(),
-                        c => panic!("oh no what is this size {:?}", c)
+                        SizeHint::RelByte => c.write_i8(distance as i8).unwrap(),
+                        SizeHint::RelWord => c.write_i16::<LittleEndian>(distance as i16).unwrap(),
+                        size => errors.push(LinkError::InvalidSize { size, chunk: label.clone(), location: location.clone() })
                     }
                 }
-                groups.insert(label.to_string(), word_refs);
-                bank_contents.write_all(&c.get_ref())?;
+                rom[file_offset..file_offset + chunk.data.len()].copy_from_slice(c.get_ref());
             }
+        }
+        // the base ROM's size is left as it is unless it grew
+        match base_size {
+            Some(c) if c == rom.len() => header::fix_checksum(&mut rom, mapper.header_offset()),
+            _ => header::finish(&mut rom, mapper.header_offset())
+        }
+        w.write_all(&rom)
+    }
+}
+
+// Prefix of the chunks holding RATS tags, which are made again every time the chunks are placed
+const RATS: &str = "*RATS ";
+
+// A RATS tag for a block of `size` bytes: "STAR", the size of everything after it minus one and
+// the inverse of that. What follows it is a marker with the patch it's from, then the block.
+fn rats_chunk(size: usize, patch_id: u32) -> LabeledChunk {
+    let mut chunk = LabeledChunk::padding(0, None);
+    chunk.diverging = false;
+    let len = (size + 8 - 1) as u16;
+    chunk.data.extend_from_slice(b"STAR");
+    chunk.data.write_u16::<LittleEndian>(len).unwrap();
+    chunk.data.write_u16::<LittleEndian>(!len).unwrap();
+    chunk.data.extend_from_slice(&rats_marker(patch_id));
+    chunk
+}
 
-            if *bank_id == 0 {
-                let (b, start) = self.refs["*Start"];
-                if b != 0 { Err("bank for Start not 0")?; }
-                let (b, nmi) = self.refs["*NMI"];
-                if b != 0 { Err("bank for VBlank not 0")?; }
-                let (b, irq) = self.refs.get("*IRQ").unwrap_or(&(0, 0x7FFF)).clone();
-                if b != 0 { Err("bank for IRQ not 0")?; }
-                let (b, brk) = self.refs.get("*BRK").unwrap_or(&(0, 0x7FFF)).clone();
-                if b != 0 { Err("bank for BRK not 0")?; }
-                let h = header(start as u16, nmi as u16, irq as u16, brk as u16);
-                bank_contents.resize(0x7FC0, 0x00);
-                bank_contents.extend_from_slice(&h.data);
+fn rats_marker(patch_id: u32) -> [u8; 8] {
+    let mut marker = *b"PIPE\0\0\0\0";
+    (&mut marker[4..]).write_u32::<LittleEndian>(patch_id).unwrap();
+    marker
+}
+
+// The length of the block after the RATS tag at `pos`, if there is one
+fn rats_tag(rom: &[u8], pos: usize) -> Option<usize> {
+    let tag = rom.get(pos..pos + 8)?;
+    if &tag[..4] != b"STAR" { return None; }
+    let len = tag[4] as u16 | (tag[5] as u16) << 8;
+    let inverse = tag[6] as u16 | (tag[7] as u16) << 8;
+    if len ^ inverse != 0xFFFF { return None; }
+    Some(len as usize + 1)
+}
+
+// Past the 512 KiB of the original SMW, as a SNES address so it works with any mapper
+const DEFAULT_FREESPACE: u32 = 0x108000;
+
+// Shorter runs of $00 in a base ROM are more likely part of some table than free space
+const MIN_FREE: usize = 0x80;
+// and the zeros right after data might still belong to it
+const FREE_MARGIN: usize = 0x10;
+
+// File ranges of the free space in a base ROM from `start` on: long enough runs of $00 that aren't
+// protected by a RATS tag, and blocks tagged by an earlier build of the same patch (returned
+// separately too)
+fn scan_base(rom: &[u8], start: usize, patch_id: u32) -> (Vec<(usize, usize)>, Vec<(usize, usize)>) {
+    fn close(free: &mut Vec<(usize, usize)>, run: Option<(usize, bool)>, end: usize) {
+        if let Some((start, ours)) = run {
+            if ours || end.saturating_sub(start) >= MIN_FREE { free.push((start, end)); }
+        }
+    }
+    let mut free = Vec::new();
+    let mut reclaimed = Vec::new();
+    // where the current run starts, and whether it has one of our old blocks in it
+    let mut run = None;
+    let mut after_data = false;
+    let mut pos = start;
+    while pos < rom.len() {
+        if let Some(len) = rats_tag(rom, pos) {
+            let end = (pos + 8 + len).min(rom.len());
+            if rom.get(pos + 8..pos + 16) == Some(&rats_marker(patch_id)[..]) {
+                reclaimed.push((pos, end));
+                run = Some((run.map_or(pos, |c: (usize, bool)| c.0.min(pos)), true));
             } else {
-                bank_contents.resize(0x8000, 0x00);
+                close(&mut free, run.take(), pos);
             }
-            w.write_all(&bank_contents)?;
-        }
-        //let mut f = ::std::fs::File::create("out-graph.json").unwrap();
-        //writeln!(f, "{:?}", groups);
-        Ok(())
-    }
-}
-
-fn header(entry: u16, nmi: u16, irq: u16, brk: u16) -> LabeledChunk {
-    ((|| {
-    let mut chunk = LabeledChunk::default();
-    // LOROM only, for now
-    chunk.pin(0x7FC0);
-    let mut title = "SUPER MARIOWORLD     ".as_bytes().to_vec();
-    title.resize(21, b' ');
-    chunk.data.write_all(&title)?;
-    chunk.data.write_all(&[
-    // ROM makeup, type, size (TODO!), SRAM size, destination code
-        0x20, 0x02, 0x10, 0x01, 0x01, 0x01 ])?;
-    chunk.data.write_u8(0x00)?;                      // Version
-    chunk.data.write_u16::<LittleEndian>(0xFFFF)?;   // ROM checksum complement stub
-    chunk.data.write_u16::<LittleEndian>(0x0000)?;   // ROM checksum stub
-    // interrupt vectors (TODO)
-    // NATIVE
-    chunk.data.write_u16::<LittleEndian>(0xFFFF)?;
-    chunk.data.write_u16::<LittleEndian>(0xFFFF)?;
-    chunk.data.write_u16::<LittleEndian>(0xFFFF)?;   // COP enable
-    chunk.data.write_u16::<LittleEndian>(brk)?;   // BRK
-    chunk.data.write_u16::<LittleEndian>(0xFFFF)?;   // ABORT
-    chunk.data.write_u16::<LittleEndian>(nmi)?;      // NMI
-    chunk.data.write_u16::<LittleEndian>(0xFFFF)?;   // RESET (unused)
-    chunk.data.write_u16::<LittleEndian>(irq)?;   // IRQ
-    // EMULATION
-    chunk.data.write_u16::<LittleEndian>(0xFFFF)?;
-    chunk.data.write_u16::<LittleEndian>(0xFFFF)?;
-    chunk.data.write_u16::<LittleEndian>(0xFFFF)?;   // COP enable
-    chunk.data.write_u16::<LittleEndian>(0xFFFF)?;   // unused
-    chunk.data.write_u16::<LittleEndian>(0xFFFF)?;   // ABORT
-    chunk.data.write_u16::<LittleEndian>(0xFFFF)?;   // NMI
-    chunk.data.write_u16::<LittleEndian>(entry)?;    // RESET (execution begins here)
-    chunk.data.write_u16::<LittleEndian>(0xFFFF)?;   // IRQ
-    assert_eq!(chunk.data.len(), 0x40);
-    Ok(chunk) })(): Result<_,Box<Error>>).unwrap()
+            after_data = false;
+            pos = end;
+            continue;
+        }
+        if rom[pos] == 0x00 {
+            run = run.or(Some((if after_data { pos + FREE_MARGIN } else { pos }, false)));
+        } else {
+            close(&mut free, run.take(), pos);
+            after_data = true;
+        }
+        pos += 1;
+    }
+    close(&mut free, run, rom.len());
+    (free, reclaimed)
+}
+
+// Private labels are `Label@path/to/file.asm` in here, symbol files only get the file's name
+fn symbol_name(label: &str) -> String {
+    match label.find('@') {
+        Some(i) => {
+            let file = Path::new(&label[i + 1..]).file_stem().map_or(String::new(), |c| c.to_string_lossy().into_owned());
+            format!("{}@{}", &label[..i], file)
+        },
+        None => label.to_string()
+    }
+}
+
+enum Grow {
+    Branch,
+    Operand(SizeHint)
+}
+
+// Moves everything after `offset` in the chunk back by `grow` bytes
+fn shift(chunk: &mut LabeledChunk, offset: usize, grow: usize) {
+    for r in chunk.pending_exprs.iter_mut() {
+        if r.offset > offset { r.offset += grow; }
+        r.expr.each_mut(|c| if let ExprNode::LabelOffset(d) = c {
+            if *d > offset as isize { *d += grow as isize; }
+        });
+    }
+    for c in chunk.locals.iter_mut() {
+        if c.1 > offset { c.1 += grow; }
+    }
+    for c in chunk.lines.iter_mut() {
+        if c.0 > offset { c.0 += grow; }
+    }
+}
+
+// Turns the branch with its operand at `offset` into a BRL, or a branch with the opposite
+// condition over one
+fn relax_branch(chunk: &mut LabeledChunk, offset: usize) {
+    let opcode = chunk.data[offset - 1];
+    let (code, operand, grow) = match opcode {
+        // BRA
+        0x80 => (vec![0x82, 0, 0], offset, 1),
+        // BPL, BMI, BVC, BVS, BCC, BCS, BNE, BEQ
+        c if c & 0x1F == 0x10 => (vec![c ^ 0x20, 3, 0x82, 0, 0], offset + 2, 3),
+        _ => return
+    };
+    chunk.data.splice(offset - 1..offset + 1, code);
+    shift(chunk, offset, grow);
+    if let Some(r) = chunk.pending_exprs.iter_mut().find(|c| c.offset == offset) {
+        r.offset = operand;
+        r.expr.size = SizeHint::RelWord;
+    }
+}
+
+// Gives the operand at `offset` a bigger addressing mode
+fn resize_operand(chunk: &mut LabeledChunk, offset: usize, size: SizeHint) {
+    let (code, old) = {
+        let r = match chunk.pending_exprs.iter_mut().find(|c| c.offset == offset) {
+            Some(c) => c,
+            None => return
+        };
+        let (name, mode) = match r.sizing.as_mut() {
+            Some(c) => c,
+            None => return
+        };
+        let old = r.expr.size.bytes().unwrap_or(0);
+        *mode = match mode.resize(size) {
+            Some(c) => c,
+            None => return
+        };
+        let mut code = Vec::new();
+        if Instruction::new(name, *mode).write_to(&mut code).is_err() { return; }
+        r.expr.size = size;
+        (code, old)
+    };
+    let grow = code.len() - 1 - old;
+    chunk.data.splice(offset - 1..offset + old, code);
+    shift(chunk, offset, grow);
+}
+
+// Whether two addresses are the same memory, through the mirrors of WRAM, the registers and the ROM
+fn same_location(mapper: Mapper, a: u32, b: u32) -> bool {
+    // the first 8KiB of WRAM and the registers are in every bank that has them at all
+    let system = |c: u32| {
+        let (bank, low) = (c >> 16 & 0xFF, c & 0xFFFF);
+        if bank & 0x40 == 0 && low < 0x8000 || bank == 0x7E && low < 0x2000 { Some(low) } else { None }
+    };
+    a == b || match (system(a), system(b)) {
+        (Some(c), Some(d)) => c == d,
+        (None, None) => mapper.to_file(a).is_some() && mapper.to_file(a) == mapper.to_file(b),
+        _ => false
+    }
+}
+
+// What a chunk at `pc` assumes the direct page and data bank registers to be
+fn registers(chunk: &LabeledChunk, pc: u32) -> (u32, u32) {
+    (chunk.direct_page.unwrap_or(0) as u32, chunk.data_bank.map_or(pc >> 16 & 0xFF, |c| c as u32))
+}
+
+// The smallest operand size (at least `current`) the instruction has that reaches `addr` with
+// the direct page and data bank at `dp` and `db`
+fn operand_size(mapper: Mapper, name: &str, mode: AddressingMode, current: SizeHint, addr: u32, (dp, db): (u32, u32)) -> Option<SizeHint> {
+    let low = addr & 0xFFFF;
+    let current = current.bytes().unwrap_or(0);
+    [SizeHint::Byte, SizeHint::Word, SizeHint::Long].iter().cloned()
+        .filter(|c| c.bytes().unwrap_or(0) >= current)
+        .filter(|c| mode.resize(*c).map_or(false, |mode| Instruction::new(name, mode).write_to(io::sink()).is_ok()))
+        .find(|c| match c {
+            SizeHint::Byte => low >= dp && low - dp < 0x100 && same_location(mapper, addr, low),
+            SizeHint::Word => same_location(mapper, addr, db << 16 | low),
+            _ => true
+        })
+}
+
+fn size_name(size: SizeHint) -> &'static str {
+    match size {
+        SizeHint::Byte => "byte",
+        SizeHint::Word => "word",
+        SizeHint::Long => "long",
+        _ => "value"
+    }
 }
 
 fn micros(now: Instant) -> u64 {
@@ -283,30 +1169,680 @@ fn micros(now: Instant) -> u64 {
     elapsed.as_secs()*1000000 + elapsed.subsec_nanos() as u64/1000
 }
 
-pub fn link<W: Write, I: Iterator<Item=CompileData>>(writer: W, iter: I) {
-    let mut banks = Banks::new();
-    let mut now = Instant::now();
-    for c in iter {
-        match c {
-            CompileData::Chunk { label, chunk } => {
-                let len = chunk.data.len();
-                let c = banks.append_chunk(label.clone(),chunk);
-                match c {
-                    Some(a) => {
-                        println!("[\x1B[38;5;117m{: >7}µs\x1B[0m] {: >24}: $\x1B[38;5;118m{:06X}\x1B[0m (size: \x1B[38;5;118m{:04X}\x1B[0m)", micros(now), label, a, len);
-                    },
-                    None => println!("WARNING: can't fit label {} (size {})", label,len)
+#[derive(Debug,Clone)]
+pub struct LinkOptions {
+    // 0: errors only, 1: summary, 2: chunk placement
+    pub verbosity: u8,
+    // overrides #![mapper(..)] in the source
+    pub mapper: Option<Mapper>,
+    // in bytes, overrides #![max_rom_size(..)]
+    pub max_size: Option<usize>,
+    // ROM to patch (without a copier header), everything it covers is left alone except for
+    // pinned chunks, its free space, the ROM size and the checksum
+    pub base: Option<Vec<u8>>,
+    // SNES address free space in the base ROM is searched from (default: past the first 512 KiB)
+    pub freespace_start: Option<u32>,
+    // tells the blocks of this patch from others, so they can be reclaimed by the next build
+    pub patch_id: u32
+}
+
+impl Default for LinkOptions {
+    fn default() -> Self {
+        LinkOptions { verbosity: 1, mapper: None, max_size: None, base: None, freespace_start: None, patch_id: 0 }
+    }
+}
+
+// Global settings have to be known before anything is placed, so they're looked up in every
+// chunk first
+#[derive(Default)]
+struct Settings {
+    mapper: Option<Mapper>,
+    max_size: Option<usize>,
+    header: Header
+}
+
+fn find_settings(items: &[CompileData], errors: &mut Vec<LinkError>) -> Settings {
+    let mut settings = Settings::default();
+    let mut mismatches = Vec::new();
+    for i in items {
+        let attrs = match i {
+            CompileData::Chunk { chunk, .. } => &chunk.attrs,
+            CompileData::Define { attrs, .. } => attrs,
+            CompileData::Error(_) => continue
+        };
+        for a in attrs {
+            let res = match a {
+                Attribute::Mapper(c) => set_once(&mut settings.mapper, c, "mapper"),
+                Attribute::MaxRomSize(c) => set_once(&mut settings.max_size, c, "max_rom_size"),
+                c => settings.header.apply(c)
+            };
+            // global attributes are repeated for every chunk after them, only report once
+            if let Err(name) = res {
+                if !mismatches.contains(&name) {
+                    errors.push(LinkError::ConflictingAttribute(name));
+                    mismatches.push(name);
                 }
-                now = Instant::now();
+            }
+        }
+    }
+    settings
+}
+
+// Names of the vectors set anywhere
+fn vectors(items: &[CompileData]) -> Vec<&'static str> {
+    let mut names = Vec::new();
+    for i in items {
+        let attrs = match i {
+            CompileData::Chunk { chunk, .. } => &chunk.attrs,
+            CompileData::Define { attrs, .. } => attrs,
+            CompileData::Error(_) => continue
+        };
+        for name in attrs.iter().filter_map(Attribute::vector_name) {
+            if !names.contains(&name) { names.push(name); }
+        }
+    }
+    names
+}
+
+// Private labels are renamed to `Label@file`, along with the references to them from that file,
+// so files can't see each other's. Everything else shares one namespace.
+fn resolve_visibility(chunks: &mut Vec<(String, LabeledChunk)>, defines: &mut Vec<(String, Vec<Attribute>, Expression, Option<(Location, u32)>)>, errors: &mut Vec<LinkError>) {
+    let file = |c: &Option<(Location, u32)>| c.as_ref().map(|c| c.0.file().to_string());
+    let mut public = HashMap::new();
+    let mut private = HashMap::new();
+    let mut imports = Vec::new();
+    for (label, chunk) in chunks.iter() {
+        for i in chunk.attrs.iter() {
+            if let Attribute::Import(name) = i {
+                if !imports.iter().any(|c: &(String, _)| c.0 == *name) {
+                    imports.push((name.clone(), chunk.location.clone()));
+                }
+            }
+        }
+        // the header and the code before the first label of every file, and replacements, which
+        // may have the same name as what they replace
+        if label.starts_with('*') || replaces(chunk).is_some() { continue; }
+        let attr = |f: fn(&Attribute) -> bool| chunk.attrs.iter().any(f);
+        let export = attr(|c| if let Attribute::Export = c { true } else { false });
+        let first = match file(&chunk.location) {
+            Some(file) if !export && attr(|c| if let Attribute::Private = c { true } else { false }) => {
+                private.insert((file, label.clone()), chunk.location.clone())
             },
-            CompileData::Define { label, attrs, expr } => {
-                banks.add_define(label, attrs, expr);
+            _ => public.insert(label.clone(), chunk.location.clone())
+        };
+        if let Some(first) = first {
+            errors.push(LinkError::DuplicateLabel { label: label.clone(), location: chunk.location.clone(), first });
+        }
+    }
+    for (label, location) in imports {
+        if !public.contains_key(&label) { errors.push(LinkError::MissingImport { label, location }); }
+    }
+    if private.is_empty() { return; }
+    let rename = |file: &Option<String>, label: &mut String| match file {
+        Some(file) if private.contains_key(&(file.clone(), label.clone())) => *label = format!("{}@{}", label, file),
+        _ => {}
+    };
+    for (label, chunk) in chunks.iter_mut() {
+        rename(&file(&chunk.location), label);
+        for r in chunk.pending_exprs.iter_mut() {
+            let file = file(&r.location);
+            r.expr.each_mut(|c| if let ExprNode::Label(d) = c { rename(&file, d) });
+        }
+    }
+    // they're filled in wherever they're used, so it's about where they were defined
+    for (_, _, expr, location) in defines.iter_mut() {
+        let file = file(location);
+        expr.each_mut(|c| if let ExprNode::Label(d) = c { rename(&file, d) });
+    }
+}
+
+// The label a chunk replaces, from #[replace(..)]
+fn replaces(chunk: &LabeledChunk) -> Option<&str> {
+    chunk.attrs.iter().filter_map(|c| if let Attribute::Replace(c) = c { Some(&**c) } else { None }).next()
+}
+
+// Chunks with #[replace(Label)] take the place of `Label`: they're moved to where it was in the
+// source, so whatever fell through into it still does, and take over its name, pin, bank and
+// vectors. The original is dropped.
+fn replace_chunks(chunks: &mut Vec<(String, LabeledChunk)>, errors: &mut Vec<LinkError>, warnings: &mut Vec<LinkError>) {
+    let mut replaced = HashSet::new();
+    let mut i = 0;
+    while i < chunks.len() {
+        let target = match replaces(&chunks[i].1) {
+            Some(c) => c.to_string(),
+            None => { i += 1; continue; }
+        };
+        let location = chunks[i].1.location.clone();
+        if !replaced.insert(target.clone()) {
+            errors.push(LinkError::ReplacedTwice { label: target, location });
+            chunks.remove(i);
+            continue;
+        }
+        let original = match chunks.iter().position(|c| c.0 == target && replaces(&c.1).is_none()) {
+            Some(c) => c,
+            None => {
+                errors.push(LinkError::ReplaceNotFound { label: target, location });
+                i += 1;
+                continue;
+            }
+        };
+        let (name, mut chunk) = chunks.remove(i);
+        let original = if original > i { original - 1 } else { original };
+        let (_, old) = mem::replace(&mut chunks[original], (target.clone(), LabeledChunk::default()));
+        if old.pinned.is_some() && chunk.size() > old.size() {
+            errors.push(LinkError::ReplacementTooBig { label: target.clone(), size: chunk.size(), max: old.size(), location: location.clone() });
+        }
+        if old.diverging && !chunk.diverging {
+            errors.push(LinkError::ReplacementFallsThrough { label: target.clone(), location: location.clone() });
+        } else if !old.diverging && chunk.diverging {
+            if let Some(next) = chunks.get(original + 1) {
+                warnings.push(LinkError::ReplacementDiverges { label: target.clone(), next: next.0.clone(), location: location.clone() });
+            }
+        }
+        chunk.pinned = chunk.pinned.or(old.pinned);
+        chunk.bank_hint = chunk.bank_hint.or(old.bank_hint);
+        // so it's not looked at again
+        chunk.attrs.retain(|c| if let Attribute::Replace(_) = c { false } else { true });
+        chunk.attrs.extend(old.attrs.into_iter().filter(|c| c.vector_name().is_some()));
+        chunks[original].1 = chunk;
+        // references to the replacement by its own name
+        if name != target {
+            for (_, c) in chunks.iter_mut() {
+                for r in c.pending_exprs.iter_mut() {
+                    r.expr.each_mut(|c| if let ExprNode::Label(d) = c { if *d == name { *d = target.clone() } });
+                }
+            }
+        }
+    }
+}
+
+// Returns the symbols of everything that was placed
+pub fn link<W: Write, I: Iterator<Item=CompileData>>(writer: W, iter: I, options: &LinkOptions) -> Result<Symbols, LinkErrors> {
+    let items = iter.collect::<Vec<_>>();
+    let mut errors = Vec::new();
+    let settings = find_settings(&items, &mut errors);
+    let mapper = options.mapper.or(settings.mapper).unwrap_or_default();
+    let max_size = options.max_size.or(settings.max_size).unwrap_or(mapper.max_size());
+    // a base ROM brings its own header, only the vectors set in the source replace its ones
+    let header = match options.base {
+        Some(_) => header::vector_chunks(&vectors(&items)),
+        None => vec![("*header".to_string(), settings.header.chunk(mapper))]
+    };
+    let max_size = max_size.max(options.base.as_ref().map_or(0, |c| c.len()));
+    let mut banks = Banks::new(mapper, settings.header, max_size, options.base.clone());
+    banks.errors = errors;
+    banks.patch_id = options.patch_id;
+    let freespace = match options.freespace_start {
+        Some(c) => mapper.to_file(c).unwrap_or_else(|| {
+            banks.errors.push(LinkError::UnmappedFreespace(c));
+            0
+        }),
+        None => mapper.to_file(DEFAULT_FREESPACE).unwrap_or(0)
+    };
+    banks.scan_base(freespace);
+    let header = header.into_iter().map(|(label, chunk)| CompileData::Chunk { label, chunk });
+    let mut chunks = Vec::new();
+    let mut defines = Vec::new();
+    for c in header.chain(items) {
+        match c {
+            // pinned code in a base ROM falls through into what was already there, not into the
+            // next label
+            CompileData::Chunk { label, mut chunk } => {
+                if options.base.is_some() && chunk.pinned.is_some() { chunk.diverging = true; }
+                chunks.push((label, chunk))
             },
-            c => panic!("{:?}",c) // todo: handle
+            CompileData::Define { label, attrs, expr, location } => defines.push((label, attrs, expr, location)),
+            CompileData::Error(e) => banks.errors.push(LinkError::Compile(e))
         }
     }
-    println!("Writing..");
+    resolve_visibility(&mut chunks, &mut defines, &mut banks.errors);
+    for (label, attrs, expr, _) in defines {
+        banks.add_define(label, attrs, expr);
+    }
+    replace_chunks(&mut chunks, &mut banks.errors, &mut banks.warnings);
+    // Chunks can grow once they're placed (see `Banks::growth`), then everything is placed again
+    let order = chunks.iter().enumerate().map(|(i, c)| (c.0.clone(), i)).collect::<HashMap<_,_>>();
+    let mut placed;
+    loop {
+        let errors = banks.errors.len();
+        placed = banks.layout(chunks);
+        let mut grow = banks.growth();
+        // layout errors would just be reported again
+        if grow.is_empty() || banks.errors.len() > errors { break; }
+        chunks = banks.take_chunks();
+        chunks.sort_by_key(|c| order[&c.0]);
+        // from the back, so the other offsets stay the same
+        grow.sort_by(|a, b| b.1.cmp(&a.1));
+        for (label, offset, grow) in grow {
+            if let Some(c) = chunks.iter_mut().find(|c| c.0 == label) {
+                match grow {
+                    Grow::Branch => relax_branch(&mut c.1, offset),
+                    Grow::Operand(size) => resize_operand(&mut c.1, offset, size)
+                }
+            }
+        }
+    }
+    if options.verbosity > 0 {
+        for w in banks.warnings.iter() {
+            let mut diag = w.diagnostic();
+            diag.level = Level::Warning;
+            diag.emit();
+        }
+    }
+    if options.verbosity > 1 {
+        for (label, addr, len, time) in placed {
+            println!("[{}] {: >24}: ${} (size: {})",
+                format!("{: >7}µs", time).pretty() + Color(117), label,
+                format!("{:06X}", addr).pretty() + Color(118),
+                format!("{:04X}", len).pretty() + Color(118));
+        }
+    }
+    if options.verbosity > 0 { banks.print_free_space(); }
+    if options.verbosity > 0 { println!("Writing.."); }
     let now = Instant::now();
-    banks.write_to(writer).unwrap();
-    println!("Done in {}µs", micros(now));
+    if let Err(e) = banks.write_to(writer) {
+        banks.errors.push(LinkError::IO(e));
+    }
+    if !banks.errors.is_empty() { return Err(LinkErrors(banks.errors)); }
+    if options.verbosity > 0 { println!("Done in {}µs", micros(now)); }
+    Ok(banks.symbols(&order))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn chunk(len: usize, diverging: bool) -> LabeledChunk {
+        let mut chunk = LabeledChunk::padding(len, None);
+        chunk.diverging = diverging;
+        chunk
+    }
+    fn compile(src: &str) -> Vec<CompileData> {
+        use lexer::Lexer;
+        use parser::Parser;
+        use compiler::{Compiler,CompilerState};
+        let state = CompilerState::default();
+        let lexed = Lexer::new("test.asm".to_string(), src.chars().collect::<Vec<_>>().into_iter());
+        Compiler::from_iter(Parser::new(lexed, state.clone(), Vec::new()), state).collect()
+    }
+    fn build(src: &str) -> Result<Vec<u8>, LinkErrors> {
+        let mut rom = Vec::new();
+        link(&mut rom, compile(src).into_iter(), &LinkOptions { verbosity: 0, ..Default::default() })?;
+        Ok(rom)
+    }
+    // what went wrong, as it would be printed
+    fn errors(src: &str) -> Vec<String> {
+        build(src).unwrap_err().0.iter().map(|c| c.to_string()).collect()
+    }
+    #[test]
+    fn sa1() {
+        let src = "#![mapper(sa1)]\n#[start] #[nmi] #[sa1_start]\nMain:\n    RTS\n#[bank($C1)]\nTable:\n    dl Table\n";
+        let rom = build(src).unwrap();
+        assert_eq!(&rom[0x7FD5..0x7FD7], &[0x23, 0x35]);
+        let table = rom.windows(3).position(|c| c == [0x00, 0x00, 0xC1]).unwrap();
+        assert_eq!(table, 0x10000);
+        // the SA-1 vectors are only checked, and only with the SA-1 mapper
+        match &build(&src.replace("#![mapper(sa1)]\n", "").replace("#[bank($C1)]\n", "")).unwrap_err().0[..] {
+            [LinkError::NotSa1("SA1Start")] => {},
+            c => panic!("unexpected errors {:?}", c)
+        }
+    }
+    #[test]
+    fn rom_size() {
+        assert_eq!(build("#[start] #[nmi]\nMain:\n    RTS\n").unwrap().len(), 0x8000);
+        // three banks are rounded to 128 KiB in the header, and the checksum counts the last one twice
+        let rom = build("#[start] #[nmi]\nMain:\n    RTS\n#[bank($82)]\nData:\n    db 1, 2, 3\n").unwrap();
+        assert_eq!(rom.len(), 0x18000);
+        assert_eq!(&rom[0x10000..0x10003], &[1, 2, 3]);
+        assert_eq!(rom[0x7FD7], 0x07);
+        let mut mirrored = rom.clone();
+        mirrored.extend_from_slice(&rom[0x10000..]);
+        assert_eq!(rom[0x7FDE] as u16 | (rom[0x7FDF] as u16) << 8, ::header::checksum(&mirrored));
+        let items = vec![
+            CompileData::Chunk { label: "A".to_string(), chunk: chunk(0x6000, true) },
+            CompileData::Chunk { label: "B".to_string(), chunk: chunk(0x6000, true) }
+        ];
+        let options = LinkOptions { verbosity: 0, max_size: Some(0x8000), ..Default::default() };
+        match &link(io::sink(), items.into_iter(), &options).unwrap_err().0[..] {
+            [LinkError::RomFull { label, max, .. }, ..] => assert_eq!((&**label, *max), ("B", 0x8000)),
+            c => panic!("unexpected errors {:?}", c)
+        }
+    }
+    #[test]
+    fn pins() {
+        let src = "#[start] #[nmi]\nMain:\n    JMP Fixed\n#[pin($008000)]\nFixed:\n    RTS\n";
+        let rom = build(src).unwrap();
+        // Main has to go around the pinned label, and the header is pinned too
+        assert_eq!(rom[0], 0x60);
+        assert_eq!(&rom[1..4], &[0x4C, 0x00, 0x80]);
+        assert_eq!(&rom[0x7FC0..0x7FD0], b"SUPER MARIOWORLD");
+        assert_eq!(errors(&format!("{}#[pin($008000)]\nOther:\n    RTS\n#[pin($7E0000)]\nRam:\n    RTS\n", src)), vec!["Other pinned to $008000 overlaps with Fixed", "can't pin Ram (size $0001) to $7E0000"]);
+    }
+    #[test]
+    fn span_banks() {
+        let blob = |attrs: &str, code: &str| format!("#[start] #[nmi]\nMain:\n    RTS\nPtr:\n    dl Blob\n{}\nBlob:\n{}    dbx \"{}\"\n",
+            attrs, code, "AB".repeat(0x9000));
+        let rom = build(&blob("#[span_banks]", "")).unwrap();
+        let start = rom.iter().position(|c| *c == 0xAB).unwrap();
+        assert_eq!(start % 0x8000, 0);
+        assert!(rom[start..start + 0x9000].iter().all(|c| *c == 0xAB));
+        // the pointer goes to the start
+        let addr = ::mapper::Mapper::LoRom.to_snes(start);
+        assert!(rom.windows(3).any(|c| c == [addr as u8, (addr >> 8) as u8, (addr >> 16) as u8]));
+        assert_eq!(errors(&blob("", ""))[0], "Blob (size $9000) is bigger than a bank");
+        assert_eq!(errors(&blob("#[span_banks]", "    LDA #1\n"))[0], "Blob contains code, it can't be split across banks");
+    }
+    #[test]
+    fn all_errors() {
+        let src = "#[start] #[nmi]\nMain:\n    JSR Nowhere\n    FOO #1\n    LDA.l Missing\n    RTS\n";
+        let errors = build(src).unwrap_err().0;
+        let text = errors.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+        assert_eq!(text, vec!["unknown instruction FOO", "label Nowhere not found", "label Missing not found"]);
+        match &errors[2] {
+            LinkError::LabelNotFound { chunk, location, .. } => {
+                assert_eq!(&**chunk, "Main");
+                assert_eq!(location.as_ref().map(|c| c.0.line()), Some(5));
+            },
+            c => panic!("unexpected error {:?}", c)
+        }
+    }
+    #[test]
+    fn best_fit() {
+        let mut bank = Bank::new(0x100);
+        bank.append("a".to_string(), 0x10, 0x8010, chunk(0x10, true));
+        bank.append("b".to_string(), 0x40, 0x8040, chunk(0x80, true));
+        assert_eq!(bank.best_fit(0, 0x10), Some((0, 0x10)));
+        assert_eq!(bank.best_fit(0, 0x11), Some((0x20, 0x20)));
+        assert_eq!(bank.best_fit(0, 0x30), Some((0xC0, 0x40)));
+        assert_eq!(bank.best_fit(0, 0x41), None);
+        assert_eq!(bank.free(), 0x70);
+        let chains = Chain::split(vec![
+            ("a".to_string(), chunk(1, false)),
+            ("b".to_string(), chunk(1, true)),
+            ("c".to_string(), chunk(0x200, true)),
+            ("d".to_string(), chunk(1, false))
+        ]);
+        assert_eq!(chains.iter().map(|c| c.label()).collect::<Vec<_>>(), vec!["a", "c", "d"]);
+        assert_eq!(chains[0].size(), 2);
+    }
+    #[test]
+    fn chains() {
+        let fill = |len| "EA".repeat(len);
+        let src = format!("#[start] #[nmi]\nStart:\n    LDA #$11\nRunFrame:\n    LDA #$22\n    RTS\n\
+            Filler:\n    dbx \"{}\"\n    RTS\nMore:\n    dbx \"{}\"\n    RTS\n", fill(0x3FF0), fill(0x3FFE));
+        let rom = build(&src).unwrap();
+        assert!(rom.windows(5).any(|c| c == [0xA9, 0x11, 0xA9, 0x22, 0x60]));
+        assert_eq!(errors("#[start] #[nmi]\nMain:\n    RTS\n#[bank($80)]\nA:\n    NOP\n#[bank($81)]\nB:\n    RTS\n"),
+            vec!["A falls through into B, but they're in different banks ($80 and $81)"]);
+        let src = format!("#[start] #[nmi]\nMain:\n    RTS\nA:\n    dbx \"{}\"\n    NOP\nB:\n    dbx \"{}\"\n    RTS\n", fill(0x5000), fill(0x5000));
+        assert_eq!(errors(&src)[0], "A and the labels it falls through into (size $A002) don't fit in a bank");
+    }
+    #[test]
+    fn same_bank() {
+        let src = "#[start] #[nmi] #[bank($80)]\nMain:\n    JSL Far\n    JSR Near\n    RTS\nNear:\n    RTS\n#[bank($81)]\nFar:\n    RTL\n";
+        assert!(build(src).is_ok());
+        assert_eq!(errors(&src.replace("JSR Near", "JSR Far")),
+            vec!["Far is in bank $81, it can't be reached with a 16-bit address from Main in bank $80"]);
+        let src = "#[start] #[nmi] #[bank($80)] #[data_bank($81)]\nMain:\n    LDA.w Table\n    RTS\nTable:\n    db 0\n";
+        assert_eq!(errors(src), vec!["Table is in bank $80, it can't be reached with a 16-bit address from Main with the data bank set to $81"]);
+    }
+    #[test]
+    fn relax() {
+        use compiler::LabelRef;
+        let mut chunk = chunk(0, true);
+        // BEQ Far : NOP : BRA .local : .local
+        chunk.data = vec![0xF0, 0x00, 0xEA, 0x80, 0x00];
+        let branch = |offset, root| LabelRef {
+            offset, expr: Expression { root, size: SizeHint::RelByte },
+            same_bank: true, program_bank: true, sizing: None, location: None
+        };
+        chunk.pending_exprs = vec![branch(1, ExprNode::Label("Far".to_string())), branch(4, ExprNode::LabelOffset(5))];
+        relax_branch(&mut chunk, 4);
+        relax_branch(&mut chunk, 1);
+        assert_eq!(chunk.data, vec![0xD0, 0x03, 0x82, 0x00, 0x00, 0xEA, 0x82, 0x00, 0x00]);
+        assert_eq!(chunk.pending_exprs[0].offset, 3);
+        assert_eq!(chunk.pending_exprs[1].offset, 7);
+        assert_eq!(chunk.pending_exprs[1].expr.root, ExprNode::LabelOffset(9));
+        assert_eq!(chunk.pending_exprs[1].expr.size, SizeHint::RelWord);
+    }
+    #[test]
+    fn relayout() {
+        let mut banks = Banks::new(Mapper::LoRom, Header::default(), 0x8000, None);
+        banks.add_define("Nmi".to_string(), vec![Attribute::NMI], Expression { root: ExprNode::Constant(0x8123), size: SizeHint::Unspecified });
+        banks.layout(vec![("Old".to_string(), chunk(0x10, true))]);
+        assert_eq!(banks.refs.get("Old"), Some(&0x808000));
+        banks.take_chunks();
+        banks.layout(vec![("New".to_string(), chunk(0x10, true))]);
+        assert_eq!(banks.refs.get("Old"), None);
+        assert_eq!(banks.refs.get("New"), Some(&0x808000));
+        assert_eq!(banks.refs.get("*NMI"), Some(&0x8123));
+    }
+    #[test]
+    fn operand_sizes() {
+        use addrmodes::AddressingMode::*;
+        let size = |name, mode, addr, regs| operand_size(Mapper::LoRom, name, mode, SizeHint::Byte, addr, regs);
+        assert_eq!(size("LDA", DirectPage(0), 0x000094, (0, 0x80)), Some(SizeHint::Byte));
+        assert_eq!(size("LDA", DirectPage(0), 0x7E0094, (0, 0x80)), Some(SizeHint::Byte));
+        assert_eq!(size("LDA", DirectPage(0), 0x000094, (0x1E00, 0x80)), Some(SizeHint::Word));
+        assert_eq!(size("LDA", DirectPage(0), 0x808123, (0, 0x80)), Some(SizeHint::Word));
+        assert_eq!(size("LDA", DirectPage(0), 0x008123, (0, 0x80)), Some(SizeHint::Word));
+        assert_eq!(size("LDA", DirectPage(0), 0x818123, (0, 0x80)), Some(SizeHint::Long));
+        assert_eq!(size("LDA", DirectPage(0), 0x7E2000, (0, 0x7E)), Some(SizeHint::Word));
+        assert_eq!(size("LDA", DPY(0), 0x7E2000, (0, 0x80)), None);
+        assert_eq!(size("LDX", DirectPage(0), 0x7E2000, (0, 0x80)), None);
+        assert_eq!(operand_size(Mapper::LoRom, "LDA", Absolute(0), SizeHint::Word, 0x94, (0, 0x80)), Some(SizeHint::Word));
+    }
+    #[test]
+    fn patch() {
+        let base = (0..0x10000).map(|c| (c * 7 + (c >> 8)) as u8).collect::<Vec<_>>();
+        let mut hijack = chunk(4, false);
+        hijack.data = vec![0x22, 0x56, 0x34, 0x12];
+        hijack.pin(0x008100);
+        let items = vec![
+            CompileData::Chunk { label: "Hijack".to_string(), chunk: hijack },
+            CompileData::Chunk { label: "Free".to_string(), chunk: chunk(0x10, true) }
+        ];
+        let mut rom = Vec::new();
+        let options = LinkOptions { verbosity: 0, base: Some(base.clone()), ..Default::default() };
+        link(&mut rom, items.into_iter(), &options).unwrap();
+        // one more bank for the unpinned chunk
+        assert_eq!(rom.len(), 0x18000);
+        assert_eq!(&rom[0x100..0x104], &[0x22, 0x56, 0x34, 0x12]);
+        let changed = (0..base.len()).filter(|&i| rom[i] != base[i]).collect::<Vec<_>>();
+        assert!(changed.iter().all(|&c| c >= 0x100 && c < 0x104 || c == 0x7FD7 || c >= 0x7FDC && c < 0x7FE0), "{:X?}", changed);
+    }
+    #[test]
+    fn freespace_start() {
+        let mut base = vec![0xEA; 0x100000];
+        for c in base[0x40000..].iter_mut() { *c = 0; }
+        let free = |start| {
+            let items = vec![CompileData::Chunk { label: "Free".to_string(), chunk: chunk(0x10, true) }];
+            let mut rom = Vec::new();
+            let options = LinkOptions { verbosity: 0, base: Some(base.clone()), freespace_start: start, ..Default::default() };
+            link(&mut rom, items.into_iter(), &options).unwrap();
+            rom.windows(4).position(|c| c == b"STAR")
+        };
+        // $108000 in LoROM
+        assert_eq!(free(None), Some(0x80000));
+        assert_eq!(free(Some(0x888000)), Some(0x40000));
+    }
+    #[test]
+    fn rats() {
+        let mut rom = vec![0xEA; 0x400];
+        for c in rom[0x10..0x200].iter_mut() { *c = 0; }
+        // someone else's block in the middle of the zeros, and one of ours from the last build
+        let mut tag = rats_chunk(0x10 - 8, 1).data;
+        rom[0x100..0x110].copy_from_slice(&tag);
+        tag = rats_chunk(0x10, 2).data;
+        rom[0x280..0x290].copy_from_slice(&tag);
+        assert_eq!(rats_tag(&rom, 0x100), Some(0x10));
+        assert_eq!(rats_tag(&rom, 0x101), None);
+        // a few zeros in a table, and a long run right after data
+        for c in rom[0x300..0x320].iter_mut() { *c = 0; }
+        for c in rom[0x340..].iter_mut() { *c = 0; }
+        let (free, reclaimed) = scan_base(&rom, 0x14, 2);
+        assert_eq!(free, vec![(0x14, 0x100), (0x118, 0x200), (0x280, 0x2A0), (0x350, 0x400)]);
+        assert_eq!(reclaimed, vec![(0x280, 0x2A0)]);
+    }
+    #[test]
+    fn replace() {
+        let with = |name: &str, diverging, attrs: Vec<Attribute>| {
+            let mut c = chunk(2, diverging);
+            c.attrs = attrs;
+            (name.to_string(), c)
+        };
+        let mut pinned = with("Fixed", true, vec![Attribute::NMI]);
+        pinned.1.pin(0x00C000);
+        let mut chunks = vec![
+            with("Main", false, vec![]),
+            with("Init", true, vec![]),
+            pinned,
+            with("Patch", true, vec![Attribute::Replace("Init".to_string())]),
+            with("Fixed", true, vec![Attribute::Replace("Fixed".to_string())])
+        ];
+        chunks[0].1.pending_exprs.push(::compiler::LabelRef {
+            offset: 0, expr: Expression { root: ExprNode::Label("Patch".to_string()), size: SizeHint::Word },
+            same_bank: true, program_bank: true, sizing: None, location: None
+        });
+        let (mut errors, mut warnings) = (Vec::new(), Vec::new());
+        replace_chunks(&mut chunks, &mut errors, &mut warnings);
+        assert!(errors.is_empty() && warnings.is_empty());
+        assert_eq!(chunks.iter().map(|c| &*c.0).collect::<Vec<_>>(), vec!["Main", "Init", "Fixed"]);
+        assert_eq!(chunks[0].1.pending_exprs[0].expr.root, ExprNode::Label("Init".to_string()));
+        assert_eq!(chunks[2].1.pinned, Some(0x00C000));
+        assert!(chunks[2].1.attrs.iter().any(|c| c.vector_name() == Some("NMI")));
+        let mut chunks = vec![
+            with("Main", false, vec![]),
+            with("Init", true, vec![]),
+            with("A", true, vec![Attribute::Replace("Main".to_string())]),
+            with("B", false, vec![Attribute::Replace("Init".to_string())]),
+            with("C", true, vec![Attribute::Replace("Init".to_string())])
+        ];
+        replace_chunks(&mut chunks, &mut errors, &mut warnings);
+        match (&errors[..], &warnings[..]) {
+            ([LinkError::ReplacementFallsThrough { .. }, LinkError::ReplacedTwice { .. }], [LinkError::ReplacementDiverges { label, next, .. }]) => {
+                assert_eq!((&**label, &**next), ("Main", "Init"));
+            },
+            c => panic!("unexpected errors {:?}", c)
+        }
+    }
+    #[test]
+    fn visibility() {
+        use std::rc::Rc;
+        use compiler::LabelRef;
+        let at = |file: &str| Some((Location::new(Rc::new(file.to_string()), 1, 1, 0), 4));
+        let label = |name: &str, file: &str, attrs: Vec<Attribute>| {
+            let mut c = chunk(3, true);
+            c.location = at(file);
+            c.attrs = attrs;
+            c.pending_exprs.push(LabelRef {
+                offset: 1, expr: Expression { root: ExprNode::Label("Init".to_string()), size: SizeHint::Word },
+                same_bank: true, program_bank: true, sizing: None, location: at(file)
+            });
+            (name.to_string(), c)
+        };
+        let mut chunks = vec![
+            label("Init", "a.asm", vec![Attribute::Private]),
+            label("Main", "a.asm", vec![Attribute::Import("Sound".to_string())]),
+            label("Init", "b.asm", vec![Attribute::Private]),
+            label("Sound", "b.asm", vec![Attribute::Private, Attribute::Export])
+        ];
+        let mut defines = vec![("Ptr".to_string(), vec![], Expression { root: ExprNode::Label("Init".to_string()), size: SizeHint::Unspecified }, at("b.asm"))];
+        let mut errors = Vec::new();
+        resolve_visibility(&mut chunks, &mut defines, &mut errors);
+        assert!(errors.is_empty());
+        assert_eq!(chunks.iter().map(|c| &*c.0).collect::<Vec<_>>(), vec!["Init@a.asm", "Main", "Init@b.asm", "Sound"]);
+        assert_eq!(chunks[1].1.pending_exprs[0].expr.root, ExprNode::Label("Init@a.asm".to_string()));
+        assert_eq!(chunks[3].1.pending_exprs[0].expr.root, ExprNode::Label("Init@b.asm".to_string()));
+        assert_eq!(defines[0].2.root, ExprNode::Label("Init@b.asm".to_string()));
+        let mut chunks = vec![
+            label("Main", "a.asm", vec![Attribute::Import("Sound".to_string())]),
+            label("Main", "b.asm", vec![]),
+            label("Sound", "b.asm", vec![Attribute::Private])
+        ];
+        resolve_visibility(&mut chunks, &mut Vec::new(), &mut errors);
+        match &errors[..] {
+            [LinkError::DuplicateLabel { label: a, .. }, LinkError::MissingImport { label: b, .. }] => {
+                assert_eq!((&**a, &**b), ("Main", "Sound"));
+            },
+            c => panic!("unexpected errors {:?}", c)
+        }
+    }
+    #[test]
+    fn symbols() {
+        // BEQ .loop : NOP : .loop : ..inner NOP
+        let mut main = chunk(4, true);
+        main.data = vec![0xF0, 0x01, 0xEA, 0xEA];
+        main.attrs = vec![Attribute::Start, Attribute::NMI];
+        main.locals = vec![("loop".to_string(), 3), ("loop.inner".to_string(), 3)];
+        relax_branch(&mut main, 1);
+        let define = |label: &str, root| CompileData::Define { label: label.to_string(), attrs: vec![], expr: Expression { root, size: SizeHint::Unspecified }, location: None };
+        let items = vec![
+            CompileData::Chunk { label: "Main".to_string(), chunk: main },
+            define("Ptr", ExprNode::BinOp { op: ::expression::BinOp::Add, lhs: Box::new(ExprNode::Label("Main".to_string())), rhs: Box::new(ExprNode::Constant(2)) }),
+            define("Lives", ExprNode::Constant(5)),
+            define("Unknown", ExprNode::Label("Nowhere".to_string()))
+        ];
+        let options = LinkOptions { verbosity: 0, ..Default::default() };
+        let symbols = link(io::sink(), items.into_iter(), &options).unwrap();
+        let labels = symbols.labels.iter().map(|c| (&*c.0, c.1)).collect::<Vec<_>>();
+        assert_eq!(labels, vec![("Main", 0x808000), ("Main.loop", 0x808006), ("Main.loop.inner", 0x808006)]);
+        assert_eq!(symbols.constants, vec![("Lives".to_string(), 5), ("Ptr".to_string(), 0x808002)]);
+    }
+    #[test]
+    fn private_symbols() {
+        use std::rc::Rc;
+        let mut main = chunk(1, true);
+        main.attrs = vec![Attribute::Start, Attribute::NMI];
+        let mut init = chunk(2, true);
+        init.location = Some((Location::new(Rc::new("src/sound/init.asm".to_string()), 1, 1, 0), 4));
+        init.attrs = vec![Attribute::Private];
+        init.locals = vec![("loop".to_string(), 1)];
+        let items = vec![
+            CompileData::Chunk { label: "Main".to_string(), chunk: main },
+            CompileData::Chunk { label: "Init".to_string(), chunk: init }
+        ];
+        let symbols = link(io::sink(), items.into_iter(), &LinkOptions { verbosity: 0, ..Default::default() }).unwrap();
+        let mut names = symbols.labels.iter().map(|c| &*c.0).collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec!["Init@init", "Init@init.loop", "Main"]);
+        let mut wla = Vec::new();
+        symbols.write(::cli::SymbolFormat::Wla, &mut wla).unwrap();
+        let wla = String::from_utf8(wla).unwrap();
+        assert!(wla.contains(" Init@init.loop\n") && !wla.contains('/'), "{}", wla);
+    }
+    #[test]
+    fn local_branches() {
+        let src = |bne| format!("#[start] #[nmi]\nMain:\n    BRL .end\n{}    dbx \"{}\"\n.end\n    RTS\n",
+            if bne { "    BNE .end\n" } else { "" }, "00".repeat(200));
+        // BRL counts from the end of its 3 bytes
+        let rom = build(&src(false)).unwrap();
+        assert_eq!(&rom[..3], &[0x82, 200, 0x00]);
+        match &build(&src(true)).unwrap_err().0[..] {
+            [LinkError::BranchTooFar { distance, size, location, .. }] => {
+                assert_eq!((*distance, *size), (200, SizeHint::RelByte));
+                assert_eq!(location.as_ref().map(|c| c.0.line()), Some(4));
+            },
+            c => panic!("unexpected errors {:?}", c)
+        }
+    }
+    #[test]
+    fn data_ranges() {
+        let src = "#[start] #[nmi]\nMain:\n    RTS\ndefine Big 300\ndefine Neg -1\ndefine TooNeg -129\n\
+            Table:\n    db Neg, Big, TooNeg\n    dw Neg, Big*1000\n    dl Big*1000\n";
+        let errors = build(src).unwrap_err().0;
+        let values = errors.iter().map(|c| match c {
+            LinkError::OutOfRange { value, size, .. } => (*value, *size),
+            c => panic!("unexpected error {:?}", c)
+        }).collect::<Vec<_>>();
+        assert_eq!(values, vec![(300, SizeHint::Byte), (-129, SizeHint::Byte), (300000, SizeHint::Word)]);
+        assert_eq!(errors[0].to_string(), "$12C doesn't fit in a byte");
+        assert_eq!(errors[1].to_string(), "-$81 doesn't fit in a byte");
+    }
+    #[test]
+    fn private_data() {
+        let src = "#[start] #[nmi]\nMain:\n    LDA.w Next\n    RTS\ndefine Next Table+2\n\
+            #[private]\nTable:\n    dw Table, Next\n";
+        let rom = build(src).unwrap();
+        assert_eq!(&rom[..8], &[0xAD, 0x06, 0x80, 0x60, 0x04, 0x80, 0x06, 0x80]);
+    }
 }
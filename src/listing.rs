@@ -0,0 +1,85 @@
+// Assembly listings: every source line next to the address and the bytes it turned into, after
+// linking, so operand sizes, relaxed branches and label addresses are the final ones.
+//
+// $808000  A2 1C 80                  3      LDX.w #.data
+//
+// Lines without any bytes (comments, defines, ..) are listed too, as long as the source file can
+// still be read.
+
+use std::collections::HashMap;
+use std::io::{self,Write};
+
+use diagnostics::source_line;
+use symbols::Symbols;
+
+const BYTES_PER_ROW: usize = 8;
+// Anything longer (like an incbin) only gets its first rows listed
+const MAX_ROWS: usize = 4;
+
+pub fn write<W: Write>(symbols: &Symbols, rom: &[u8], mut w: W) -> io::Result<()> {
+    // the last line listed in each file
+    let mut listed = HashMap::new();
+    let mut file = None;
+    for line in symbols.lines.iter() {
+        let name = line.location.file();
+        if file != Some(name) {
+            writeln!(w, "{}; {}", if file.is_some() { "\n" } else { "" }, name)?;
+            file = Some(name);
+        }
+        let number = line.location.line();
+        let last = listed.entry(name).or_insert(0);
+        for i in *last + 1..number {
+            match source_line(name, i) {
+                Some(text) => writeln!(w, "{:33}{:>5}  {}", "", i, text)?,
+                None => break
+            }
+        }
+        // the rest of a line with more than one statement only gets the bytes
+        let text = if number == *last { String::new() } else { source_line(name, number).unwrap_or_default() };
+        *last = number.max(*last);
+        let bytes = rom.get(line.offset..line.offset + line.len).unwrap_or(&[]);
+        let mut rows = bytes.chunks(BYTES_PER_ROW).map(hex);
+        let row = format!("${:06X}  {:<24}{:>5}  {}", line.address, rows.next().unwrap_or_default(), number, text);
+        writeln!(w, "{}", row.trim_right())?;
+        for (i, row) in rows.enumerate() {
+            if i + 2 == MAX_ROWS && bytes.len() > MAX_ROWS * BYTES_PER_ROW {
+                writeln!(w, "{:9}.. ({} bytes)", "", bytes.len())?;
+                break;
+            }
+            writeln!(w, "{:9}{}", "", row)?;
+        }
+    }
+    Ok(())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|c| format!("{:02X}", c)).collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use lexer::Location;
+    use symbols::SourceLine;
+    #[test]
+    fn rows() {
+        let at = |line| Location::new(Rc::new("<none>".to_string()), line, 5, 0);
+        let line = |line, address: u32, offset, len| SourceLine { location: at(line), address, offset, len };
+        let symbols = Symbols {
+            lines: vec![line(1, 0x808000, 0, 0), line(2, 0x808000, 0, 3), line(2, 0x808003, 3, 1), line(4, 0x808004, 4, 40)],
+            ..Default::default()
+        };
+        let rom = (0..0x40).collect::<Vec<u8>>();
+        let mut buf = Vec::new();
+        write(&symbols, &rom, &mut buf).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), "; <none>\n\
+            $808000                              1\n\
+            $808000  00 01 02                    2\n\
+            $808003  03                          2\n\
+            $808004  04 05 06 07 08 09 0A 0B     4\n\
+            \x20        0C 0D 0E 0F 10 11 12 13\n\
+            \x20        14 15 16 17 18 19 1A 1B\n\
+            \x20        .. (40 bytes)\n");
+    }
+}
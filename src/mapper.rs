@@ -0,0 +1,220 @@
+// Memory mappers: how the ROM file is laid out in the SNES address space.
+// Everything is in terms of "banks", which are the unit the linker places chunks in (32KiB for
+// LoROM-likes, 64KiB for HiROM-likes), numbered by their position in the file.
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Mapper {
+    LoRom,
+    HiRom,
+    // Like LoROM, but the second 4MiB are mapped to $00-$7D
+    ExLoRom,
+    // Like HiROM, but the second 4MiB are mapped to $40-$7D (and $00-$3F for the upper halves)
+    ExHiRom,
+    // SA-1 with the default Super MMC layout: $00-$3F and $80-$BF each map 2MiB LoROM-style,
+    // $C0-$FF map all 4MiB HiROM-style
+    Sa1
+}
+
+impl Default for Mapper {
+    fn default() -> Self {
+        Mapper::LoRom
+    }
+}
+
+impl Mapper {
+    pub fn parse(s: &str) -> Option<Self> {
+        use self::Mapper::*;
+        Some(match &*s.to_lowercase() {
+            "lorom" => LoRom,
+            "hirom" => HiRom,
+            "exlorom" => ExLoRom,
+            "exhirom" => ExHiRom,
+            "sa1" | "sa-1" => Sa1,
+            _ => return None
+        })
+    }
+    pub fn name(self) -> &'static str {
+        use self::Mapper::*;
+        match self {
+            LoRom => "lorom",
+            HiRom => "hirom",
+            ExLoRom => "exlorom",
+            ExHiRom => "exhirom",
+            Sa1 => "sa1"
+        }
+    }
+    pub fn bank_size(self) -> usize {
+        use self::Mapper::*;
+        match self {
+            LoRom | ExLoRom | Sa1 => 0x8000,
+            HiRom | ExHiRom => 0x10000
+        }
+    }
+    // The biggest ROM this mapper can address
+    pub fn max_size(self) -> usize {
+        use self::Mapper::*;
+        match self {
+            LoRom | HiRom | Sa1 => 0x400000,
+            // $7E and $7F are WRAM
+            ExLoRom => 0x400000 + 0x7E * 0x8000,
+            ExHiRom => 0x400000 + 0x3E * 0x10000
+        }
+    }
+    pub fn max_banks(self) -> usize {
+        self.max_size() / self.bank_size()
+    }
+    // The map mode byte in the internal header
+    pub fn map_mode(self) -> u8 {
+        use self::Mapper::*;
+        match self {
+            LoRom => 0x20,
+            HiRom => 0x21,
+            ExLoRom => 0x22,
+            ExHiRom => 0x25,
+            Sa1 => 0x23
+        }
+    }
+    // The cartridge type byte in the internal header
+    pub fn cartridge_type(self) -> u8 {
+        match self {
+            // ROM, SA-1, RAM and battery
+            Mapper::Sa1 => 0x35,
+            // ROM, RAM and battery
+            _ => 0x02
+        }
+    }
+    // File offset -> SNES address. For LoROM this prefers the FastROM mirror at $80-$FF.
+    pub fn to_snes(self, offset: usize) -> u32 {
+        use self::Mapper::*;
+        let offset = offset as u32;
+        match self {
+            LoRom => 0x800000 | (offset >> 15) << 16 | 0x8000 | offset & 0x7FFF,
+            HiRom => 0xC00000 | offset,
+            ExLoRom if offset < 0x400000 => LoRom.to_snes(offset as usize),
+            ExLoRom => LoRom.to_snes(offset as usize - 0x400000) & 0x7FFFFF,
+            ExHiRom if offset < 0x400000 => 0xC00000 | offset,
+            ExHiRom => 0x400000 | (offset - 0x400000),
+            Sa1 => {
+                let bank = offset >> 15;
+                (if bank < 0x40 { bank } else { bank + 0x40 }) << 16 | 0x8000 | offset & 0x7FFF
+            }
+        }
+    }
+    // Like `to_snes`, but SA-1 chunks placed in $C0-$FF get their address from there
+    pub fn address(self, offset: usize, bank_hint: Option<u8>) -> u32 {
+        match (self, bank_hint) {
+            (Mapper::Sa1, Some(c)) if c >= 0xC0 => 0xC00000 | offset as u32,
+            _ => self.to_snes(offset)
+        }
+    }
+    // SNES address -> file offset, if the address is mapped to the ROM at all
+    pub fn to_file(self, addr: u32) -> Option<usize> {
+        use self::Mapper::*;
+        let bank = addr >> 16 & 0xFF;
+        let low = addr & 0xFFFF;
+        if bank == 0x7E || bank == 0x7F { return None; }
+        let offset = match self {
+            LoRom if low >= 0x8000 => (bank & 0x7F) << 15 | low & 0x7FFF,
+            HiRom if bank & 0x40 != 0 || low >= 0x8000 => (bank & 0x3F) << 16 | low,
+            ExLoRom if low >= 0x8000 => {
+                let offset = (bank & 0x7F) << 15 | low & 0x7FFF;
+                if bank & 0x80 != 0 { offset } else { offset + 0x400000 }
+            },
+            ExHiRom if bank & 0x40 != 0 || low >= 0x8000 => {
+                let offset = (bank & 0x3F) << 16 | low;
+                if bank & 0x80 != 0 { offset } else { offset + 0x400000 }
+            },
+            Sa1 if bank >= 0xC0 => (bank & 0x3F) << 16 | low,
+            // $40-$7F is BW-RAM and I-RAM
+            Sa1 if bank & 0x40 == 0 && low >= 0x8000 => ((bank & 0x3F) | (bank & 0x80) >> 1) << 15 | low & 0x7FFF,
+            _ => return None
+        };
+        Some(offset as usize)
+    }
+    // Which bank a #[bank(..)] hint refers to
+    pub fn bank_index(self, snes_bank: u8) -> Option<usize> {
+        let addr = (snes_bank as u32) << 16;
+        self.to_file(addr).or(self.to_file(addr | 0x8000)).map(|c| c / self.bank_size())
+    }
+    pub fn bank_address(self, bank: usize) -> u32 {
+        self.to_snes(bank * self.bank_size())
+    }
+    // Where the internal header is in the file, always at $00:FFC0
+    pub fn header_offset(self) -> usize {
+        self.to_file(0xFFC0).unwrap()
+    }
+    // Where code reachable from the interrupt vectors can start ($00:8000)
+    pub fn vector_start(self) -> usize {
+        self.to_file(0x8000).unwrap()
+    }
+    pub fn vector_bank(self) -> usize {
+        self.vector_start() / self.bank_size()
+    }
+    // Whether the vectors (which are 16-bit, in bank $00) can point to this address
+    pub fn reachable_from_vectors(self, addr: u32) -> bool {
+        self.to_file(addr).is_some() && self.to_file(addr & 0xFFFF) == self.to_file(addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::Mapper::*;
+    #[test]
+    fn addresses() {
+        assert_eq!(LoRom.to_snes(0x8123), 0x818123);
+        assert_eq!(LoRom.to_file(0x018123), Some(0x8123));
+        assert_eq!(LoRom.to_file(0x7E0000), None);
+        assert_eq!(HiRom.to_snes(0x18123), 0xC18123);
+        assert_eq!(HiRom.to_file(0x018123), Some(0x18123));
+        assert_eq!(HiRom.to_file(0x010123), None);
+        assert_eq!(ExHiRom.to_snes(0x408000), 0x408000);
+        assert_eq!(ExHiRom.to_file(0x008000), Some(0x408000));
+        assert_eq!(ExLoRom.to_snes(0x400000), 0x008000);
+        assert_eq!(Sa1.to_snes(0x208000), 0x818000);
+        assert_eq!(Sa1.to_file(0xC18000), Some(0x18000));
+        assert_eq!(Sa1.to_file(0x408000), None);
+        assert_eq!(Sa1.address(0x10000, Some(0xC1)), 0xC10000);
+        assert_eq!(Sa1.bank_index(0xC1), Some(2));
+        for m in [LoRom, HiRom, ExLoRom, ExHiRom, Sa1].iter() {
+            for &offset in [0, 0x7FFF, 0x8000, 0x123456, m.max_size() - 1].iter() {
+                assert_eq!(m.to_file(m.to_snes(offset)), Some(offset), "{:?} ${:06X}", m, offset);
+            }
+        }
+        assert_eq!(LoRom.header_offset(), 0x7FC0);
+        assert_eq!(HiRom.header_offset(), 0xFFC0);
+        assert_eq!(ExHiRom.header_offset(), 0x40FFC0);
+        assert_eq!(ExLoRom.header_offset(), 0x407FC0);
+        assert_eq!(HiRom.vector_start(), 0x8000);
+        assert_eq!(ExHiRom.vector_bank(), 0x40);
+        assert_eq!(HiRom.bank_index(0xC3), Some(3));
+        assert!(HiRom.reachable_from_vectors(0xC08000));
+        assert!(!HiRom.reachable_from_vectors(0xC00000));
+    }
+    #[test]
+    fn sa1() {
+        // $00-$3F and $80-$BF each show 2MiB LoROM-style
+        assert_eq!(Sa1.to_file(0x008000), Some(0));
+        assert_eq!(Sa1.to_file(0x3FFFFF), Some(0x1FFFFF));
+        assert_eq!(Sa1.to_file(0x808000), Some(0x200000));
+        assert_eq!(Sa1.to_file(0xBFFFFF), Some(0x3FFFFF));
+        assert_eq!(Sa1.to_file(0x003000), None);
+        // BW-RAM and I-RAM
+        assert_eq!(Sa1.to_file(0x408000), None);
+        assert_eq!(Sa1.to_file(0x600000), None);
+        // $C0-$FF show all 4MiB HiROM-style
+        assert_eq!(Sa1.to_file(0xC00000), Some(0));
+        assert_eq!(Sa1.to_file(0xE08000), Sa1.to_file(0x818000));
+        assert_eq!(Sa1.to_file(0xFFFFFF), Some(0x3FFFFF));
+        assert_eq!(Sa1.to_snes(0x1FFFFF), 0x3FFFFF);
+        assert_eq!(Sa1.to_snes(0x200000), 0x808000);
+        assert_eq!(Sa1.address(0x200000, Some(0xE0)), 0xE00000);
+        assert_eq!(Sa1.address(0x200000, Some(0x80)), 0x808000);
+        assert_eq!(Sa1.bank_index(0x80), Some(0x40));
+        assert_eq!(Sa1.bank_index(0xE0), Some(0x40));
+        assert_eq!(Sa1.header_offset(), 0x7FC0);
+        assert!(Sa1.reachable_from_vectors(0x00C000));
+        assert!(!Sa1.reachable_from_vectors(0x808000));
+        assert_eq!((Sa1.map_mode(), Sa1.cartridge_type()), (0x23, 0x35));
+    }
+}
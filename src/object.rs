@@ -0,0 +1,656 @@
+// Object files, written by `piped compile` and combined into a ROM by `piped link`.
+//
+// They hold exactly what the compiler hands to the linker, so linking a set of object files gives
+// the same ROM as building the sources together. Everything is little endian:
+//   "PIPEDOBJ", format version (u16)
+//   sources: (path, FNV-1a hash of the contents) for every file read while compiling
+//   items: a tag byte, then a chunk or a define
+// Strings are a u32 length and UTF-8, lists a u32 count, options a 0/1 byte and then the value.
+
+use std::fs::File;
+use std::io::{self,Read,Write};
+use std::path::Path;
+use std::rc::Rc;
+
+use byteorder::{LittleEndian,ReadBytesExt,WriteBytesExt};
+
+use addrmodes::AddressingMode;
+use attributes::Attribute;
+use compiler::{CompileData,CompileError,LabelRef,LabeledChunk};
+use expression::{BinOp,Expression,ExprNode,UnOp};
+use instructions::SizeHint;
+use lexer::Location;
+use mapper::Mapper;
+
+const MAGIC: &[u8; 8] = b"PIPEDOBJ";
+// Bump this whenever the layout of anything below changes
+pub const VERSION: u16 = 6;
+
+pub struct Object {
+    pub sources: Vec<(String, u64)>,
+    pub items: Vec<CompileData>
+}
+
+impl Object {
+    // Compile errors can't be stored, they have to be reported instead
+    pub fn new<I: Iterator<Item=CompileData>>(sources: Vec<(String, u64)>, iter: I) -> Result<Self, Vec<CompileError>> {
+        let mut items = Vec::new();
+        let mut errors = Vec::new();
+        for c in iter {
+            match c {
+                CompileData::Error(e) => errors.push(e),
+                c => items.push(c)
+            }
+        }
+        if !errors.is_empty() { return Err(errors); }
+        Ok(Object { sources, items })
+    }
+    pub fn write_to<W: Write>(&self, mut w: W) -> io::Result<()> {
+        w.write_all(MAGIC)?;
+        w.write_u16::<LittleEndian>(VERSION)?;
+        self.sources.encode(&mut w)?;
+        w.write_u32::<LittleEndian>(self.items.len() as u32)?;
+        for c in self.items.iter() {
+            match c {
+                CompileData::Chunk { label, chunk } => {
+                    w.write_u8(0)?;
+                    label.encode(&mut w)?;
+                    chunk.encode(&mut w)?;
+                },
+                CompileData::Define { label, attrs, expr, location } => {
+                    w.write_u8(1)?;
+                    label.encode(&mut w)?;
+                    attrs.encode(&mut w)?;
+                    expr.encode(&mut w)?;
+                    location.encode(&mut w)?;
+                },
+                CompileData::Error(_) => unreachable!("compile errors aren't stored in object files")
+            }
+        }
+        Ok(())
+    }
+    pub fn read_from<R: Read>(mut r: R) -> io::Result<Self> {
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC { return invalid("not an object file"); }
+        let version = r.read_u16::<LittleEndian>()?;
+        if version != VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("object file version {} isn't supported (expected {}), it has to be compiled again", version, VERSION)));
+        }
+        let sources = Decode::decode(&mut r)?;
+        let count = r.read_u32::<LittleEndian>()?;
+        let mut items = Vec::new();
+        for _ in 0..count {
+            items.push(match r.read_u8()? {
+                0 => CompileData::Chunk { label: Decode::decode(&mut r)?, chunk: Decode::decode(&mut r)? },
+                1 => CompileData::Define {
+                    label: Decode::decode(&mut r)?,
+                    attrs: Decode::decode(&mut r)?,
+                    expr: Decode::decode(&mut r)?,
+                    location: Decode::decode(&mut r)?
+                },
+                _ => return invalid("unknown item")
+            });
+        }
+        Ok(Object { sources, items })
+    }
+    // The sources that were changed (or removed) since the object file was written
+    pub fn changed_sources(&self) -> Vec<&str> {
+        self.sources.iter()
+            .filter(|(path, hash)| hash_file(path).ok() != Some(*hash))
+            .map(|(path, _)| &**path)
+            .collect()
+    }
+}
+
+// 64-bit FNV-1a, plenty to notice a changed source
+pub fn hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, c| (hash ^ *c as u64).wrapping_mul(0x100000001b3))
+}
+
+pub fn hash_file<P: AsRef<Path>>(path: P) -> io::Result<u64> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+    Ok(hash(&data))
+}
+
+fn invalid<T>(what: &'static str) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, format!("broken object file: {}", what)))
+}
+
+trait Encode {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()>;
+}
+
+trait Decode: Sized {
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self>;
+}
+
+impl Encode for u8 {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> { w.write_u8(*self) }
+}
+impl Decode for u8 {
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self> { r.read_u8() }
+}
+
+impl Encode for u16 {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> { w.write_u16::<LittleEndian>(*self) }
+}
+impl Decode for u16 {
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self> { r.read_u16::<LittleEndian>() }
+}
+
+impl Encode for u32 {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> { w.write_u32::<LittleEndian>(*self) }
+}
+impl Decode for u32 {
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self> { r.read_u32::<LittleEndian>() }
+}
+
+impl Encode for u64 {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> { w.write_u64::<LittleEndian>(*self) }
+}
+impl Decode for u64 {
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self> { r.read_u64::<LittleEndian>() }
+}
+
+impl Encode for i32 {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> { w.write_i32::<LittleEndian>(*self) }
+}
+impl Decode for i32 {
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self> { r.read_i32::<LittleEndian>() }
+}
+
+// sizes and offsets are stored as 64-bit, so they don't depend on the host
+impl Encode for usize {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> { w.write_u64::<LittleEndian>(*self as u64) }
+}
+impl Decode for usize {
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self> { Ok(r.read_u64::<LittleEndian>()? as usize) }
+}
+
+impl Encode for isize {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> { w.write_i64::<LittleEndian>(*self as i64) }
+}
+impl Decode for isize {
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self> { Ok(r.read_i64::<LittleEndian>()? as isize) }
+}
+
+impl Encode for bool {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> { w.write_u8(*self as u8) }
+}
+impl Decode for bool {
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self> {
+        match r.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => invalid("bool out of range")
+        }
+    }
+}
+
+impl Encode for String {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_u32::<LittleEndian>(self.len() as u32)?;
+        w.write_all(self.as_bytes())
+    }
+}
+impl Decode for String {
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self> {
+        let len = r.read_u32::<LittleEndian>()? as usize;
+        let mut buf = Vec::new();
+        r.take(len as u64).read_to_end(&mut buf)?;
+        if buf.len() != len { return invalid("string cut off"); }
+        String::from_utf8(buf).or_else(|_| invalid("string isn't UTF-8"))
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_u32::<LittleEndian>(self.len() as u32)?;
+        for c in self.iter() { c.encode(w)?; }
+        Ok(())
+    }
+}
+impl<T: Decode> Decode for Vec<T> {
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self> {
+        let len = r.read_u32::<LittleEndian>()?;
+        // don't trust the count with the allocation, a broken file just runs out of data
+        let mut out = Vec::new();
+        for _ in 0..len { out.push(T::decode(r)?); }
+        Ok(out)
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        match self {
+            None => w.write_u8(0),
+            Some(c) => { w.write_u8(1)?; c.encode(w) }
+        }
+    }
+}
+impl<T: Decode> Decode for Option<T> {
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self> {
+        Ok(if bool::decode(r)? { Some(T::decode(r)?) } else { None })
+    }
+}
+
+impl<A: Encode, B: Encode> Encode for (A, B) {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.0.encode(w)?;
+        self.1.encode(w)
+    }
+}
+impl<A: Decode, B: Decode> Decode for (A, B) {
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self> {
+        Ok((A::decode(r)?, B::decode(r)?))
+    }
+}
+
+impl Encode for Location {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.file().to_string().encode(w)?;
+        self.line().encode(w)?;
+        self.column().encode(w)?;
+        self.byte().encode(w)
+    }
+}
+impl Decode for Location {
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self> {
+        let file = Rc::new(String::decode(r)?);
+        Ok(Location::new(file, u32::decode(r)?, u32::decode(r)?, u32::decode(r)?))
+    }
+}
+
+impl Encode for SizeHint {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        use self::SizeHint::*;
+        w.write_u8(match self {
+            Implicit => 0,
+            AContext => 1,
+            XYContext => 2,
+            Byte => 3,
+            Word => 4,
+            Long => 5,
+            RelByte => 6,
+            RelWord => 7,
+            Unspecified => 8
+        })
+    }
+}
+impl Decode for SizeHint {
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self> {
+        use self::SizeHint::*;
+        Ok(match r.read_u8()? {
+            0 => Implicit,
+            1 => AContext,
+            2 => XYContext,
+            3 => Byte,
+            4 => Word,
+            5 => Long,
+            6 => RelByte,
+            7 => RelWord,
+            8 => Unspecified,
+            _ => return invalid("unknown size")
+        })
+    }
+}
+
+impl Encode for BinOp {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        use self::BinOp::*;
+        w.write_u8(match self {
+            Add => 0,
+            Sub => 1,
+            Mul => 2,
+            Div => 3,
+            And => 4,
+            Or => 5,
+            Xor => 6,
+            Lsr => 7,
+            Asl => 8
+        })
+    }
+}
+impl Decode for BinOp {
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self> {
+        use self::BinOp::*;
+        Ok(match r.read_u8()? {
+            0 => Add,
+            1 => Sub,
+            2 => Mul,
+            3 => Div,
+            4 => And,
+            5 => Or,
+            6 => Xor,
+            7 => Lsr,
+            8 => Asl,
+            _ => return invalid("unknown operator")
+        })
+    }
+}
+
+impl Encode for UnOp {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_u8(match self {
+            UnOp::Unm => 0,
+            UnOp::Not => 1
+        })
+    }
+}
+impl Decode for UnOp {
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self> {
+        Ok(match r.read_u8()? {
+            0 => UnOp::Unm,
+            1 => UnOp::Not,
+            _ => return invalid("unknown operator")
+        })
+    }
+}
+
+impl Encode for ExprNode {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        use self::ExprNode::*;
+        match self {
+            Empty => w.write_u8(0),
+            Constant(c) => { w.write_u8(1)?; c.encode(w) },
+            LabelOffset(c) => { w.write_u8(2)?; c.encode(w) },
+            LocalLabel { stack } => { w.write_u8(3)?; stack.encode(w) },
+            PosLabel { depth, id } => { w.write_u8(4)?; depth.encode(w)?; id.encode(w) },
+            NegLabel { depth, id } => { w.write_u8(5)?; depth.encode(w)?; id.encode(w) },
+            Label(c) => { w.write_u8(6)?; c.encode(w) },
+            Str(c) => { w.write_u8(7)?; c.encode(w) },
+            BinOp { op, lhs, rhs } => { w.write_u8(8)?; op.encode(w)?; lhs.encode(w)?; rhs.encode(w) },
+            UnOp(op, c) => { w.write_u8(9)?; op.encode(w)?; c.encode(w) }
+        }
+    }
+}
+impl Decode for ExprNode {
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self> {
+        use self::ExprNode::*;
+        Ok(match r.read_u8()? {
+            0 => Empty,
+            1 => Constant(Decode::decode(r)?),
+            2 => LabelOffset(Decode::decode(r)?),
+            3 => LocalLabel { stack: Decode::decode(r)? },
+            4 => PosLabel { depth: Decode::decode(r)?, id: Decode::decode(r)? },
+            5 => NegLabel { depth: Decode::decode(r)?, id: Decode::decode(r)? },
+            6 => Label(Decode::decode(r)?),
+            7 => Str(Decode::decode(r)?),
+            8 => BinOp {
+                op: Decode::decode(r)?,
+                lhs: Box::new(Decode::decode(r)?),
+                rhs: Box::new(Decode::decode(r)?)
+            },
+            9 => UnOp(Decode::decode(r)?, Box::new(Decode::decode(r)?)),
+            _ => return invalid("unknown expression")
+        })
+    }
+}
+
+impl Encode for Expression {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.root.encode(w)?;
+        self.size.encode(w)
+    }
+}
+impl Decode for Expression {
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self> {
+        Ok(Expression { root: Decode::decode(r)?, size: Decode::decode(r)? })
+    }
+}
+
+impl Encode for AddressingMode {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        use self::AddressingMode::*;
+        match *self {
+            Implied => w.write_u8(0),
+            Immediate(c) => { w.write_u8(1)?; c.encode(w) },
+            ImmediateWord(c) => { w.write_u8(2)?; c.encode(w) },
+            DirectPage(c) => { w.write_u8(3)?; c.encode(w) },
+            DPX(c) => { w.write_u8(4)?; c.encode(w) },
+            DPY(c) => { w.write_u8(5)?; c.encode(w) },
+            DPInd(c) => { w.write_u8(6)?; c.encode(w) },
+            DPIndX(c) => { w.write_u8(7)?; c.encode(w) },
+            DPIndY(c) => { w.write_u8(8)?; c.encode(w) },
+            DPIndLong(c) => { w.write_u8(9)?; c.encode(w) },
+            DPIndLongY(c) => { w.write_u8(10)?; c.encode(w) },
+            Stack(c) => { w.write_u8(11)?; c.encode(w) },
+            StackY(c) => { w.write_u8(12)?; c.encode(w) },
+            Absolute(c) => { w.write_u8(13)?; c.encode(w) },
+            AbsoluteX(c) => { w.write_u8(14)?; c.encode(w) },
+            AbsoluteY(c) => { w.write_u8(15)?; c.encode(w) },
+            AbsInd(c) => { w.write_u8(16)?; c.encode(w) },
+            AbsIndX(c) => { w.write_u8(17)?; c.encode(w) },
+            AbsIndLong(c) => { w.write_u8(18)?; c.encode(w) },
+            AbsLong(c) => { w.write_u8(19)?; c.encode(w) },
+            AbsLongX(c) => { w.write_u8(20)?; c.encode(w) },
+            Relative(c) => { w.write_u8(21)?; w.write_i8(c) },
+            RelativeWord(c) => { w.write_u8(22)?; w.write_i16::<LittleEndian>(c) },
+            BlockMove(a, b) => { w.write_u8(23)?; a.encode(w)?; b.encode(w) }
+        }
+    }
+}
+impl Decode for AddressingMode {
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self> {
+        use self::AddressingMode::*;
+        Ok(match r.read_u8()? {
+            0 => Implied,
+            1 => Immediate(Decode::decode(r)?),
+            2 => ImmediateWord(Decode::decode(r)?),
+            3 => DirectPage(Decode::decode(r)?),
+            4 => DPX(Decode::decode(r)?),
+            5 => DPY(Decode::decode(r)?),
+            6 => DPInd(Decode::decode(r)?),
+            7 => DPIndX(Decode::decode(r)?),
+            8 => DPIndY(Decode::decode(r)?),
+            9 => DPIndLong(Decode::decode(r)?),
+            10 => DPIndLongY(Decode::decode(r)?),
+            11 => Stack(Decode::decode(r)?),
+            12 => StackY(Decode::decode(r)?),
+            13 => Absolute(Decode::decode(r)?),
+            14 => AbsoluteX(Decode::decode(r)?),
+            15 => AbsoluteY(Decode::decode(r)?),
+            16 => AbsInd(Decode::decode(r)?),
+            17 => AbsIndX(Decode::decode(r)?),
+            18 => AbsIndLong(Decode::decode(r)?),
+            19 => AbsLong(Decode::decode(r)?),
+            20 => AbsLongX(Decode::decode(r)?),
+            21 => Relative(r.read_i8()?),
+            22 => RelativeWord(r.read_i16::<LittleEndian>()?),
+            23 => BlockMove(Decode::decode(r)?, Decode::decode(r)?),
+            _ => return invalid("unknown addressing mode")
+        })
+    }
+}
+
+impl Encode for Mapper {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        use self::Mapper::*;
+        w.write_u8(match self {
+            LoRom => 0,
+            HiRom => 1,
+            ExLoRom => 2,
+            ExHiRom => 3,
+            Sa1 => 4
+        })
+    }
+}
+impl Decode for Mapper {
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self> {
+        use self::Mapper::*;
+        Ok(match r.read_u8()? {
+            0 => LoRom,
+            1 => HiRom,
+            2 => ExLoRom,
+            3 => ExHiRom,
+            4 => Sa1,
+            _ => return invalid("unknown mapper")
+        })
+    }
+}
+
+impl Encode for Attribute {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        use self::Attribute::*;
+        match self {
+            Bank(c) => { w.write_u8(0)?; c.encode(w) },
+            DataBank(c) => { w.write_u8(1)?; c.encode(w) },
+            DirectPage(c) => { w.write_u8(2)?; c.encode(w) },
+            Pin(c) => { w.write_u8(3)?; c.encode(w) },
+            WarnLength(c) => { w.write_u8(4)?; c.encode(w) },
+            SpanBanks(c) => { w.write_u8(5)?; c.encode(w) },
+            RelaxBranches => w.write_u8(6),
+            Private => w.write_u8(22),
+            Export => w.write_u8(23),
+            Import(c) => { w.write_u8(24)?; c.encode(w) },
+            Replace(c) => { w.write_u8(25)?; c.encode(w) },
+            Mapper(c) => { w.write_u8(7)?; c.encode(w) },
+            MaxRomSize(c) => { w.write_u8(8)?; c.encode(w) },
+            Title(c) => { w.write_u8(9)?; c.encode(w) },
+            Region(c) => { w.write_u8(10)?; c.encode(w) },
+            Maker(c) => { w.write_u8(11)?; c.encode(w) },
+            Version(c) => { w.write_u8(12)?; c.encode(w) },
+            Sram(c) => { w.write_u8(13)?; c.encode(w) },
+            Chipset(c) => { w.write_u8(14)?; c.encode(w) },
+            Start => w.write_u8(15),
+            NMI => w.write_u8(16),
+            IRQ => w.write_u8(17),
+            BRK => w.write_u8(18),
+            SA1Start => w.write_u8(19),
+            SA1NMI => w.write_u8(20),
+            SA1IRQ => w.write_u8(21)
+        }
+    }
+}
+impl Decode for Attribute {
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self> {
+        use self::Attribute::*;
+        Ok(match r.read_u8()? {
+            0 => Bank(Decode::decode(r)?),
+            1 => DataBank(Decode::decode(r)?),
+            2 => DirectPage(Decode::decode(r)?),
+            3 => Pin(Decode::decode(r)?),
+            4 => WarnLength(Decode::decode(r)?),
+            5 => SpanBanks(Decode::decode(r)?),
+            6 => RelaxBranches,
+            22 => Private,
+            23 => Export,
+            24 => Import(Decode::decode(r)?),
+            25 => Replace(Decode::decode(r)?),
+            7 => Mapper(Decode::decode(r)?),
+            8 => MaxRomSize(Decode::decode(r)?),
+            9 => Title(Decode::decode(r)?),
+            10 => Region(Decode::decode(r)?),
+            11 => Maker(Decode::decode(r)?),
+            12 => Version(Decode::decode(r)?),
+            13 => Sram(Decode::decode(r)?),
+            14 => Chipset(Decode::decode(r)?),
+            15 => Start,
+            16 => NMI,
+            17 => IRQ,
+            18 => BRK,
+            19 => SA1Start,
+            20 => SA1NMI,
+            21 => SA1IRQ,
+            _ => return invalid("unknown attribute")
+        })
+    }
+}
+
+impl Encode for LabelRef {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.offset.encode(w)?;
+        self.expr.encode(w)?;
+        self.same_bank.encode(w)?;
+        self.program_bank.encode(w)?;
+        self.sizing.encode(w)?;
+        self.location.encode(w)
+    }
+}
+impl Decode for LabelRef {
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self> {
+        Ok(LabelRef {
+            offset: Decode::decode(r)?,
+            expr: Decode::decode(r)?,
+            same_bank: Decode::decode(r)?,
+            program_bank: Decode::decode(r)?,
+            sizing: Decode::decode(r)?,
+            location: Decode::decode(r)?
+        })
+    }
+}
+
+impl Encode for LabeledChunk {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.data.encode(w)?;
+        self.pending_exprs.encode(w)?;
+        self.attrs.encode(w)?;
+        self.diverging.encode(w)?;
+        self.has_code.encode(w)?;
+        self.bank_hint.encode(w)?;
+        self.data_bank.encode(w)?;
+        self.direct_page.encode(w)?;
+        self.pinned.encode(w)?;
+        self.location.encode(w)?;
+        self.locals.encode(w)?;
+        self.lines.encode(w)
+    }
+}
+impl Decode for LabeledChunk {
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self> {
+        Ok(LabeledChunk {
+            data: Decode::decode(r)?,
+            pending_exprs: Decode::decode(r)?,
+            attrs: Decode::decode(r)?,
+            diverging: Decode::decode(r)?,
+            has_code: Decode::decode(r)?,
+            bank_hint: Decode::decode(r)?,
+            data_bank: Decode::decode(r)?,
+            direct_page: Decode::decode(r)?,
+            pinned: Decode::decode(r)?,
+            location: Decode::decode(r)?,
+            locals: Decode::decode(r)?,
+            lines: Decode::decode(r)?
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lexer::Lexer;
+    use parser::Parser;
+    use compiler::{Compiler,CompilerState};
+    fn compile(src: &str) -> Vec<CompileData> {
+        let state = CompilerState::default();
+        let lexed = Lexer::new("test.asm".to_string(), src.chars().collect::<Vec<_>>().into_iter());
+        Compiler::from_iter(Parser::new(lexed, state.clone(), Vec::new()), state).collect()
+    }
+    #[test]
+    fn round_trip() {
+        let src = "#![mapper(hirom)]\ndefine Lives 5\n#[bank(1)] #[direct_page($0100)]\nMain:\n    LDA Data,x\n.loop\n    BRA .loop\nData:\n    db 1, 2, Lives * 2\n";
+        let object = Object::new(vec![("test.asm".to_string(), hash(src.as_bytes()))], compile(src).into_iter())
+            .unwrap_or_else(|_| panic!("compile errors"));
+        let mut buf = Vec::new();
+        object.write_to(&mut buf).unwrap();
+        let read = Object::read_from(&buf[..]).unwrap();
+        assert_eq!(read.sources, object.sources);
+        // Debug covers every field, including the ones without PartialEq
+        assert_eq!(format!("{:?}", read.items), format!("{:?}", object.items));
+        let mut again = Vec::new();
+        read.write_to(&mut again).unwrap();
+        assert_eq!(again, buf);
+    }
+    #[test]
+    fn version() {
+        let mut buf = Vec::new();
+        Object { sources: vec![], items: vec![] }.write_to(&mut buf).unwrap();
+        buf[8] = 0xFF;
+        let err = Object::read_from(&buf[..]).err().unwrap();
+        assert!(err.to_string().contains("version"));
+        assert!(Object::read_from(&b"PIPEDOB"[..]).is_err());
+    }
+}
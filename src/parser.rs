@@ -2,7 +2,8 @@ use std::fmt;
 use std::io::{self,prelude::*,BufReader};
 use std::iter::Peekable;
 
-use lexer::{Span,SpanData};
+use lexer::{Span,SpanData,Location};
+use diagnostics::Diagnostic;
 use instructions::SizeHint;
 use expression::{Expression,ExprNode,ExprError};
 use compiler::CompilerState;
@@ -156,7 +157,9 @@ pub enum Statement {
     },
     RawData {
         data: Vec<u8>,
-        pending_exprs: Vec<(usize,Expression)>
+        pending_exprs: Vec<(usize,Expression)>,
+        // the db/dw/incbin/..
+        location: Option<(Location, u32)>
     },
     Define {
         label: Span,
@@ -167,6 +170,28 @@ pub enum Statement {
     Error(ParseError)
 }
 
+impl Statement {
+    fn relocate(&mut self, location: (Location, u32)) {
+        use self::Statement::*;
+        match self {
+            Label { name, .. } | LocalLabel { name, .. } | Instruction { name, .. } => name.relocate(location),
+            Define { label, .. } => label.relocate(location),
+            RawData { location: c, .. } => *c = Some(location),
+            Nothing | Error(_) => {}
+        }
+    }
+    // Where the statement starts, as far as it's known
+    pub fn location(&self) -> Option<(Location, u32)> {
+        use self::Statement::*;
+        match self {
+            Label { name, .. } | LocalLabel { name, .. } | Instruction { name, .. } => name.location(),
+            Define { label, .. } => label.location(),
+            RawData { location, .. } => location.clone(),
+            Nothing | Error(_) => None
+        }
+    }
+}
+
 impl fmt::Display for Statement {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use self::Statement::*;
@@ -194,28 +219,57 @@ pub enum ParseError {
     GenericSyntaxError,
     UnknownAddressingMode(Span),
     IO(io::Error),
+    File(Span, io::Error),
     Unexpected(Span, &'static str),
     UnexpectedSymbol(Span),
     AttributeError(AttributeError),
     UnexpectedEOF(String),
 }
 
-// obviously TODO
 impl fmt::Display for ParseError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        use colors::prelude::*;
-        write!(f, "{}", "Error: ".pretty() + Color(1) + Bold)?;
+        use self::ParseError::*;
         match self {
-            ParseError::ExprError(ref e) => {
-                write!(f, "Expression error {:?}", e)
-            },
-            ParseError::UnknownAddressingMode(ref e) => {
-                write!(f, "Unknown addressing mode {} at {{location}}", e)
-            },
-            e => write!(f, "Other error (TODO!)\n{:?}", e)
+            ExprError(e) => write!(f, "{}", e),
+            MalformedHexString(s) => write!(f, "malformed hex string {}", s),
+            UnknownCommand(s) => write!(f, "unknown command {}", Span::coagulate(s)),
+            InvalidOpSize(s) => write!(f, "invalid operand size .{}", s),
+            GenericSyntaxError => write!(f, "syntax error"),
+            UnknownAddressingMode(s) => write!(f, "unknown addressing mode {}", s),
+            IO(e) => write!(f, "{}", e),
+            File(s, e) => write!(f, "can't open {}: {}", s, e),
+            Unexpected(s, expected) => write!(f, "unexpected {}, expected {}", s, expected),
+            UnexpectedSymbol(s) => write!(f, "unexpected symbol {}", s),
+            AttributeError(e) => write!(f, "{}", e),
+            UnexpectedEOF(s) if s.is_empty() => write!(f, "unexpected end of line"),
+            UnexpectedEOF(s) => write!(f, "unexpected end of line, expected {}", s)
+        }
+    }
+}
+
+impl ParseError {
+    pub fn location(&self) -> Option<(Location, u32)> {
+        use self::ParseError::*;
+        match self {
+            ExprError(e) => e.location(),
+            MalformedHexString(s) | InvalidOpSize(s) | UnknownAddressingMode(s)
+                | Unexpected(s, _) | UnexpectedSymbol(s) | File(s, _) => s.location(),
+            UnknownCommand(s) => Span::coagulate(s).location(),
+            AttributeError(e) => e.location(),
+            _ => None
+        }
+    }
+    pub fn diagnostic(&self) -> Diagnostic {
+        use self::ParseError::*;
+        let d = Diagnostic::error(self.to_string()).with_span(self.location());
+        match self {
+            InvalidOpSize(_) => d.with_note("valid sizes are .b, .w and .l"),
+            MalformedHexString(_) => d.with_note("hex strings need an even amount of hex digits"),
+            _ => d
         }
     }
 }
+
 use std::option::NoneError;
 impl From<NoneError> for ParseError {
     fn from(f: NoneError) -> ParseError {
@@ -227,26 +281,41 @@ pub struct Parser<S: Iterator> {
     incsrc: Option<Box<Iterator<Item=Statement>>>,
     global_attrs: Vec<Attribute>,
     state: CompilerState,
+    // Whether the rest of the line has to be skipped after an error
+    resync: bool,
+    // the `lua` statement that generated the code, everything points there instead
+    origin: Option<(Location, u32)>
 }
 impl<S: Iterator<Item=Span>> Parser<S> {
     pub fn new(iter: S, state: CompilerState, attrs: Vec<Attribute>) -> Self {
-        Self { iter: NPeekable::new(iter), state, global_attrs: attrs, incsrc: None }
+        Self { iter: NPeekable::new(iter), state, global_attrs: attrs, incsrc: None, resync: false, origin: None }
     }
     // .next() but skip whitespace
     fn skip_wsp(&mut self) -> Option<Span> {
         self.iter.by_ref().filter(|c| !c.is_whitespace()).next()
     }
-    fn define(&mut self, attrs: Vec<Attribute>) -> Result<Statement, ParseError> {
+    // Takes everything until the end of the statement, even if parsing it fails
+    fn rest_of_line(&mut self) -> Vec<Span> {
         use self::Span::*;
+        self.resync = false;
+        self.iter.by_ref()
+            .take_while(|c| c != &LineBreak && !c.is_symbol(':'))
+            .filter(|c| c != &Whitespace)
+            .collect()
+    }
+    fn filename(&mut self) -> Result<(Span, String),ParseError> {
+        let span = self.skip_wsp()?;
+        let filename = span.clone().as_string().ok_or(ParseError::Unexpected(span.clone(), "a file name"))?;
+        Ok((span, filename))
+    }
+    fn define(&mut self, attrs: Vec<Attribute>) -> Result<Statement, ParseError> {
         let label = self.skip_wsp()?;
         if !label.as_ident().is_some() { return Err(ParseError::Unexpected(label, "ident")) }
-        let iter = self.iter.by_ref()
-            .take_while(|c| c != &LineBreak && !c.is_symbol(':'))
-            .filter(|c| c != &Whitespace);
+        let line = self.rest_of_line();
         Ok(Statement::Define {
             label,
             attrs,
-            expr: Expression::parse(&mut NPeekable::new(iter), &mut self.state.borrow_mut().lls).map_err(ParseError::ExprError)?
+            expr: Expression::parse(&mut NPeekable::new(line.into_iter()), &mut self.state.borrow_mut().lls).map_err(ParseError::ExprError)?
         })
     }
     fn incsrc(&mut self, attrs: &Vec<Attribute>) -> Result<Option<Statement>,ParseError> {
@@ -255,53 +324,62 @@ impl<S: Iterator<Item=Span>> Parser<S> {
         use std::fs::File;
         use lexer;
         let state = self.state.clone();
-        let filename = self.skip_wsp()?.as_string().ok_or(ParseError::GenericSyntaxError)?;
+        let (span, filename) = self.filename()?;
         /*let file = File::open(&filename).map_err(ParseError::IO)?;
         let file = BufReader::new(file);
         let chars = file.chars().map(Result::unwrap);*/
-        println!("trying to open {}..", filename);
-        let lexed = lexer::from_filename(filename.to_string()).unwrap();
+        let path = self.state.borrow().find_file(&filename);
+        if self.state.borrow().verbosity > 2 { println!("trying to open {}..", path.display()); }
+        let lexed = lexer::from_filename(path.to_string_lossy().into_owned()).map_err(|e| ParseError::File(span, e))?;
+        self.state.borrow_mut().sources.push(path);
         let mut parsed = Box::new(Parser::new(lexed, state, self.global_attrs.clone()));
+        parsed.origin = self.origin.clone();
         let first_stmt = parsed.next();
         self.incsrc = Some(parsed);
         Ok(first_stmt)
     }
-    fn incbin(&mut self) -> Result<Statement,ParseError> {
+    fn incbin(&mut self, location: Option<(Location, u32)>) -> Result<Statement,ParseError> {
         use std::fs::File;
         use std::io::Read;
-        let filename = self.skip_wsp()?.as_string().ok_or(ParseError::GenericSyntaxError)?;
+        let (span, filename) = self.filename()?;
         let mut data = Vec::new();
-        println!("trying to open {}..", filename);
-        File::open(&filename).map_err(ParseError::IO)?.read_to_end(&mut data).map_err(ParseError::IO)?;
+        let path = self.state.borrow().find_file(&filename);
+        if self.state.borrow().verbosity > 2 { println!("trying to open {}..", path.display()); }
+        File::open(&path)
+            .and_then(|mut c| c.read_to_end(&mut data))
+            .map_err(|e| ParseError::File(span, e))?;
+        self.state.borrow_mut().sources.push(path);
         Ok(Statement::RawData {
             data,
-            pending_exprs: vec![]
+            pending_exprs: vec![],
+            location
         })
     }
-    fn lua(&mut self) -> Result<Option<Statement>,ParseError> {
+    fn lua(&mut self, location: Option<(Location, u32)>) -> Result<Option<Statement>,ParseError> {
         use lexer::Lexer;
         use std::process::Command;
-        let script = self.skip_wsp()?.as_string().ok_or(ParseError::GenericSyntaxError)?;
+        let (span, script) = self.filename()?;
         let child = Command::new("lua")
             .arg(&script)
-            .output().unwrap();//.map_err(ParseError::IO)?;
+            .output().map_err(|e| ParseError::File(span, e))?;
         let state = self.state.clone();
         // TODO: make this mess more bearable
         //let mut out = String::new();
         //child.stdout.unwrap().read_to_string(&mut out).unwrap();
-        let mut out = String::from_utf8(child.stdout).unwrap();
+        let mut out = String::from_utf8_lossy(&child.stdout).into_owned();
         let lexed = Lexer::new(script.to_string(), out.chars().collect::<Vec<_>>().into_iter());
         let mut parsed = Box::new(Parser::new(lexed, state, self.global_attrs.clone()));
+        // a script's output can't be looked at later, the line that ran it can
+        parsed.origin = self.origin.clone().or(location);
         let first_stmt = parsed.next();
         self.incsrc = Some(parsed);
         Ok(first_stmt)
     }
-    fn inline_data(&mut self, attrs: Vec<Attribute>, size: SizeHint) -> Result<Statement,ParseError> {
+    fn inline_data(&mut self, attrs: Vec<Attribute>, size: SizeHint, location: Option<(Location, u32)>) -> Result<Statement,ParseError> {
         use self::Span::*;
         use self::Statement::*;
-        let mut expr_buf = self.iter.by_ref()
-            .take_while(|c| c != &LineBreak && !c.is_symbol(':'))
-            .filter(|c| c != &Whitespace);
+        let line = self.rest_of_line();
+        let mut expr_buf = line.into_iter();
         let mut dbuf = Vec::with_capacity(16);
         let mut pending_exprs = Vec::new();
         loop {
@@ -333,10 +411,11 @@ impl<S: Iterator<Item=Span>> Parser<S> {
         }
         Ok(RawData {
             data: dbuf,
-            pending_exprs
+            pending_exprs,
+            location
         })
     }
-    fn inline_hex_data(&mut self, attrs: Vec<Attribute>) -> Result<Statement,ParseError> {
+    fn inline_hex_data(&mut self, attrs: Vec<Attribute>, location: Option<(Location, u32)>) -> Result<Statement,ParseError> {
         use self::Span::*;
         use self::Statement::*;
         let data = self.skip_wsp()?;
@@ -349,7 +428,8 @@ impl<S: Iterator<Item=Span>> Parser<S> {
             .ok_or(ParseError::MalformedHexString(data.clone()))?;
         Ok(RawData {
             data: buf,
-            pending_exprs: vec![]
+            pending_exprs: vec![],
+            location
         })
     }
     fn instruction(&mut self, attrs: Vec<Attribute>, id1: SpanData<String>) -> Result<Statement,ParseError> {
@@ -370,10 +450,7 @@ impl<S: Iterator<Item=Span>> Parser<S> {
             _ => {}
         }
         // Note: in the future, there will be no `.collect()`
-        let buf = iter.by_ref()
-            .take_while(|c| c != &LineBreak && !c.is_symbol(':'))
-            .filter(|c| c != &Whitespace)
-            .collect::<Vec<_>>();
+        let buf = self.rest_of_line();
         let arg = if buf.len() > 0 {
             Argument::parse(&buf, &self.state)?
         } else {
@@ -400,6 +477,7 @@ impl<S: Iterator<Item=Span>> Iterator for Parser<S> {
         use self::Span::*;
         use self::Statement::*;
         let mut attrs = self.global_attrs.clone();
+        self.resync = true;
         let res = (|| {
             // loop allows the use of "continue"
             loop {
@@ -421,15 +499,15 @@ impl<S: Iterator<Item=Span>> Iterator for Parser<S> {
                             Some(c) => c,
                             None => continue
                         },
-                        "lua" => match self.lua()? {
+                        "lua" => match self.lua(Some((id1.start.clone(), id1.length)))? {
                             Some(c) => c,
                             None => continue
                         },
-                        "incbin" => self.incbin()?,
-                        "db" => self.inline_data(attrs, SizeHint::Byte)?,
-                        "dw" => self.inline_data(attrs, SizeHint::Word)?,
-                        "dl" => self.inline_data(attrs, SizeHint::Long)?,
-                        "dbx" => self.inline_hex_data(attrs)?,
+                        "incbin" => self.incbin(Some((id1.start.clone(), id1.length)))?,
+                        "db" => self.inline_data(attrs, SizeHint::Byte, Some((id1.start.clone(), id1.length)))?,
+                        "dw" => self.inline_data(attrs, SizeHint::Word, Some((id1.start.clone(), id1.length)))?,
+                        "dl" => self.inline_data(attrs, SizeHint::Long, Some((id1.start.clone(), id1.length)))?,
+                        "dbx" => self.inline_hex_data(attrs, Some((id1.start.clone(), id1.length)))?,
                         _ => self.instruction(attrs, id1)?
                     }
                 },
@@ -457,6 +535,7 @@ impl<S: Iterator<Item=Span>> Iterator for Parser<S> {
                         if !iter.next()?.is_symbol('[') { return Err(ParseError::GenericSyntaxError) }
                         let buf = iter.by_ref()
                             .take_while(|c| !c.is_symbol(']'))
+                            .filter(|c| !c.is_whitespace())
                             .collect::<Vec<_>>();
                         let c = buf.split(|c| c.is_symbol(','))
                             .map(Attribute::from_span)
@@ -470,6 +549,7 @@ impl<S: Iterator<Item=Span>> Iterator for Parser<S> {
                         let iter = &mut self.iter;
                         let buf = iter.by_ref()
                             .take_while(|c| !c.is_symbol(']'))
+                            .filter(|c| !c.is_whitespace())
                             .collect::<Vec<_>>();
                         let c = buf.split(|c| c.is_symbol(','))
                             .map(Attribute::from_span)
@@ -480,13 +560,24 @@ impl<S: Iterator<Item=Span>> Iterator for Parser<S> {
                     },
                     _ => { return Err(ParseError::GenericSyntaxError) },
                 }
-                c => { println!("c: {:?}", c); Nothing }
+                c => return Err(ParseError::UnexpectedSymbol(c))
             })) }
         })();
         match res {
-            Ok(Some(c)) => Some(c),
+            Ok(Some(mut c)) => {
+                if let Some(ref origin) = self.origin { c.relocate(origin.clone()); }
+                Some(c)
+            },
             Ok(None) => None,
-            Err(e) => Some(Statement::Error(e))
+            Err(e) => {
+                // Skip to the next line, so one mistake doesn't cause a cascade of errors
+                if self.resync {
+                    while let Some(c) = self.iter.next() {
+                        if c == Span::LineBreak { break; }
+                    }
+                }
+                Some(Statement::Error(e))
+            }
         }
     }
 }
@@ -520,4 +611,18 @@ mod tests {
             else { panic!("Wrong statement type {:?}", parsed[2]); };
         assert_eq!(data, &vec![16, 32, 48]);
     }*/
+    #[test]
+    fn generated() {
+        let program = "Main:\n  LDA #1\n  db 2\n";
+        let lexer = Lexer::new("gen.lua".to_string(), program.chars().collect::<Vec<_>>().into_iter());
+        let mut parser = Parser::new(lexer, Default::default(), Vec::new());
+        let origin = Location::new(::std::rc::Rc::new("main.asm".to_string()), 12, 5, 0);
+        parser.origin = Some((origin, 3));
+        let parsed = parser.collect::<Vec<_>>();
+        assert_eq!(parsed.len(), 3);
+        for c in parsed.iter() {
+            let (location, len) = c.location().unwrap();
+            assert_eq!((location.file(), location.line(), location.column(), len), ("main.asm", 12, 5, 3));
+        }
+    }
 }
@@ -0,0 +1,131 @@
+// Symbol files for debuggers: WLA-DX .sym (bsnes-plus), Mesen .mlb and plain JSON
+
+use std::io::{self,Write};
+
+use cli::SymbolFormat;
+use lexer::Location;
+use mapper::Mapper;
+
+#[derive(Debug,Default)]
+pub struct Symbols {
+    pub mapper: Mapper,
+    // (name, SNES address), sorted by address, local labels as `Label.local` and private ones as
+    // `Label@file` (without the directory and extension)
+    pub labels: Vec<(String, u32)>,
+    // defines with a known value, sorted by name
+    pub constants: Vec<(String, i32)>,
+    // every label and statement that was placed, in source order
+    pub lines: Vec<SourceLine>
+}
+
+// Where the bytes of a label or statement ended up
+#[derive(Debug,Clone)]
+pub struct SourceLine {
+    pub location: Location,
+    pub address: u32,
+    // in the ROM file
+    pub offset: usize,
+    pub len: usize
+}
+
+impl Symbols {
+    pub fn write<W: Write>(&self, format: SymbolFormat, w: W) -> io::Result<()> {
+        match format {
+            SymbolFormat::Wla => self.write_wla(w),
+            SymbolFormat::Mlb => self.write_mlb(w),
+            SymbolFormat::Json => self.write_json(w)
+        }
+    }
+    fn write_wla<W: Write>(&self, mut w: W) -> io::Result<()> {
+        writeln!(w, "; wla symbolic information file")?;
+        writeln!(w, "; written by piped")?;
+        writeln!(w, "\n[labels]")?;
+        for (name, addr) in self.labels.iter() {
+            writeln!(w, "{:02x}:{:04x} {}", addr >> 16, addr & 0xFFFF, name)?;
+        }
+        writeln!(w, "\n[definitions]")?;
+        for (name, value) in self.constants.iter() {
+            writeln!(w, "{:08x} {}", *value as u32, name)?;
+        }
+        Ok(())
+    }
+    // Labels are ROM offsets, constants only make it in if they look like a RAM address or
+    // a register
+    fn write_mlb<W: Write>(&self, mut w: W) -> io::Result<()> {
+        for (name, addr) in self.labels.iter() {
+            if let Some(offset) = self.mapper.to_file(*addr) {
+                writeln!(w, "SnesPrgRom:{:X}:{}", offset, mlb_name(name))?;
+            }
+        }
+        for (name, value) in self.constants.iter() {
+            let (kind, addr) = match *value {
+                0x0000...0x1FFF => ("SnesWorkRam", *value),
+                0x2100...0x43FF => ("SnesRegister", *value),
+                0x7E0000...0x7FFFFF => ("SnesWorkRam", *value - 0x7E0000),
+                _ => continue
+            };
+            writeln!(w, "{}:{:X}:{}", kind, addr, mlb_name(name))?;
+        }
+        Ok(())
+    }
+    fn write_json<W: Write>(&self, mut w: W) -> io::Result<()> {
+        writeln!(w, "{{\n  \"labels\": [")?;
+        for (i, (name, addr)) in self.labels.iter().enumerate() {
+            let offset = self.mapper.to_file(*addr).map_or("null".to_string(), |c| c.to_string());
+            let comma = if i + 1 < self.labels.len() { "," } else { "" };
+            writeln!(w, "    {{ \"name\": {}, \"address\": {}, \"offset\": {} }}{}", json_string(name), addr, offset, comma)?;
+        }
+        writeln!(w, "  ],\n  \"constants\": [")?;
+        for (i, (name, value)) in self.constants.iter().enumerate() {
+            let comma = if i + 1 < self.constants.len() { "," } else { "" };
+            writeln!(w, "    {{ \"name\": {}, \"value\": {} }}{}", json_string(name), value, comma)?;
+        }
+        writeln!(w, "  ]\n}}")
+    }
+}
+
+// Mesen only takes letters, digits, `_` and `@` in names
+fn mlb_name(name: &str) -> String {
+    name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '@' { c } else { '_' }).collect()
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c)
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn formats() {
+        let symbols = Symbols {
+            mapper: Mapper::LoRom,
+            labels: vec![("Reset".to_string(), 0x808000), ("Reset.loop".to_string(), 0x808004), ("Data@b".to_string(), 0x818000)],
+            constants: vec![("Lives".to_string(), 5), ("Volume".to_string(), 0x2140), ("Mode\"".to_string(), -1)],
+            lines: vec![]
+        };
+        let text = |format| {
+            let mut buf = Vec::new();
+            symbols.write(format, &mut buf).unwrap();
+            String::from_utf8(buf).unwrap()
+        };
+        let wla = text(SymbolFormat::Wla);
+        assert!(wla.contains("[labels]\n80:8000 Reset\n80:8004 Reset.loop\n81:8000 Data@b\n"));
+        assert!(wla.contains("[definitions]\n00000005 Lives\n00002140 Volume\nffffffff Mode\"\n"));
+        assert_eq!(text(SymbolFormat::Mlb), "SnesPrgRom:0:Reset\nSnesPrgRom:4:Reset_loop\nSnesPrgRom:8000:Data@b\n\
+            SnesWorkRam:5:Lives\nSnesRegister:2140:Volume\n");
+        let json = text(SymbolFormat::Json);
+        assert!(json.contains("{ \"name\": \"Reset.loop\", \"address\": 8421380, \"offset\": 4 },\n"));
+        assert!(json.contains("{ \"name\": \"Mode\\\"\", \"value\": -1 }\n  ]\n}"));
+    }
+}